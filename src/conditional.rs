@@ -0,0 +1,164 @@
+use nu_protocol::{LabeledError, Value};
+use std::time::{Duration, SystemTime};
+
+/// Outcome of evaluating the request's preconditions (RFC 9110 section 13.2.2)
+#[derive(Debug, PartialEq, Eq)]
+pub enum Precondition {
+    /// Send the response as usual
+    Proceed,
+    /// 304 Not Modified
+    NotModified,
+    /// 412 Precondition Failed
+    Failed,
+}
+
+/// Evaluate If-Match, If-Unmodified-Since, If-None-Match and If-Modified-Since
+///
+/// Only GET and HEAD requests are evaluated: by the time the response is known, the
+/// closure has already run, so for other methods it must check the preconditions in
+/// `$req.headers` itself before changing anything.
+pub fn evaluate_preconditions(
+    request: &tiny_http::Request,
+    etag: Option<&str>,
+    last_modified: Option<SystemTime>,
+) -> Precondition {
+    if !matches!(
+        request.method(),
+        tiny_http::Method::Get | tiny_http::Method::Head
+    ) {
+        return Precondition::Proceed;
+    }
+    let header = |name: &'static str| {
+        request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv(name))
+            .map(|h| h.value.as_str())
+    };
+
+    if let Some(if_match) = header("If-Match") {
+        if !etag_matches(if_match, etag, true) {
+            return Precondition::Failed;
+        }
+    } else if let Some(since) = header("If-Unmodified-Since") {
+        if let (Some(last_modified), Ok(since)) = (last_modified, httpdate::parse_http_date(since))
+        {
+            if last_modified > since {
+                return Precondition::Failed;
+            }
+        }
+    }
+
+    if let Some(if_none_match) = header("If-None-Match") {
+        if etag_matches(if_none_match, etag, false) {
+            return Precondition::NotModified;
+        }
+    } else if let Some(since) = header("If-Modified-Since") {
+        if let (Some(last_modified), Ok(since)) = (last_modified, httpdate::parse_http_date(since))
+        {
+            if last_modified <= since {
+                return Precondition::NotModified;
+            }
+        }
+    }
+
+    Precondition::Proceed
+}
+
+/// Whether a list of entity tags (or `*`) matches the response's tag
+///
+/// `*` matches any current representation, even one without a tag (a streamed body).
+/// Strong comparison requires both tags to be strong; weak comparison only looks at
+/// the opaque part.
+fn etag_matches(list: &str, etag: Option<&str>, strong: bool) -> bool {
+    if list.trim() == "*" {
+        return true;
+    }
+    let Some(etag) = etag else {
+        return false;
+    };
+
+    let (weak, opaque) = split_etag(etag);
+    parse_etag_list(list)
+        .into_iter()
+        .any(|(other_weak, other)| other == opaque && !(strong && (weak || other_weak)))
+}
+
+/// Split a quoted entity tag into its weakness and opaque part
+fn split_etag(etag: &str) -> (bool, &str) {
+    match etag.strip_prefix("W/") {
+        Some(rest) => (true, rest),
+        None => (false, etag),
+    }
+}
+
+/// Parse a comma separated list of entity tags, skipping anything malformed
+fn parse_etag_list(list: &str) -> Vec<(bool, &str)> {
+    let mut etags = Vec::new();
+    let mut rest = list;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ',' || c.is_ascii_whitespace());
+        if rest.is_empty() {
+            return etags;
+        }
+
+        let (weak, tag) = split_etag(rest);
+        match tag.strip_prefix('"').and_then(|t| t.find('"')) {
+            Some(end) => {
+                etags.push((weak, &tag[..end + 2]));
+                rest = &tag[end + 2..];
+            }
+            // Malformed: skip to the next comma
+            None => rest = tag.find(',').map_or("", |i| &tag[i..]),
+        }
+    }
+}
+
+/// Strong ETag for a buffered body (64-bit FNV-1a of its content)
+pub fn body_etag(body: &[u8]) -> String {
+    let hash = body.iter().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    });
+    format!("\"{hash:016x}\"")
+}
+
+/// Convert the `etag` field of `http.response` to a quoted entity tag
+///
+/// Unquoted values are quoted; `W/"..."` is kept as a weak tag.
+pub fn etag_from_value(value: &Value) -> Result<String, LabeledError> {
+    let span = value.span();
+    let etag = value.coerce_string().map_err(|_| {
+        LabeledError::new("Invalid response etag").with_label("expected a string", span)
+    })?;
+
+    let (weak, tag) = split_etag(&etag);
+    let opaque = tag
+        .strip_prefix('"')
+        .and_then(|t| t.strip_suffix('"'))
+        .unwrap_or(tag);
+    if !opaque
+        .bytes()
+        .all(|b| b == 0x21 || (0x23..=0x7E).contains(&b))
+    {
+        return Err(LabeledError::new("Invalid response etag")
+            .with_label("must be printable ASCII without spaces or quotes", span));
+    }
+
+    Ok(format!("{}\"{opaque}\"", if weak { "W/" } else { "" }))
+}
+
+/// Convert the `last_modified` field of `http.response` (a date or an HTTP date string)
+pub fn last_modified_from_value(value: &Value) -> Result<SystemTime, LabeledError> {
+    let span = value.span();
+    let error = || {
+        LabeledError::new("Invalid response last_modified")
+            .with_label("expected a date after 1970", span)
+    };
+    match value {
+        Value::Date { val, .. } => u64::try_from(val.timestamp())
+            .map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+            .map_err(|_| error()),
+        Value::String { val, .. } => httpdate::parse_http_date(val).map_err(|_| error()),
+        _ => Err(error()),
+    }
+}
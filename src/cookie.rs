@@ -0,0 +1,197 @@
+use nu_protocol::{LabeledError, Record, Span, Value};
+
+/// Parse the Cookie header(s) into a record of name to value
+///
+/// If a cookie name appears more than once, the first value wins: browsers send
+/// the cookie with the most specific path first.
+pub fn cookies_to_value(headers: &[tiny_http::Header], span: Span) -> Value {
+    let mut record = Record::new();
+    let pairs = headers
+        .iter()
+        .filter(|h| h.field.equiv("Cookie"))
+        .flat_map(|h| h.value.as_str().split(';'));
+    for pair in pairs {
+        let Some((name, value)) = pair.split_once('=') else {
+            continue;
+        };
+        let name = name.trim();
+        if name.is_empty() || record.contains(name) {
+            continue;
+        }
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .unwrap_or(value);
+        record.push(name, Value::string(value, span));
+    }
+    Value::record(record, span)
+}
+
+/// Serialize the `cookies` record of `http.response` into Set-Cookie header values
+///
+/// Each field is a cookie: either its value, or a record with `value` and any of
+/// `path`, `domain`, `max_age`, `expires`, `http_only`, `secure` and `same_site`.
+pub fn set_cookies_from_value(value: &Value) -> Result<Vec<String>, LabeledError> {
+    let record = value.as_record().map_err(|_| {
+        LabeledError::new("Invalid response cookies").with_label("expected a record", value.span())
+    })?;
+
+    record
+        .iter()
+        .map(|(name, cookie)| set_cookie(name, cookie))
+        .collect()
+}
+
+fn set_cookie(name: &str, cookie: &Value) -> Result<String, LabeledError> {
+    let span = cookie.span();
+    if name.is_empty() || !name.bytes().all(is_token_byte) {
+        return Err(LabeledError::new(format!("Invalid cookie name: {name:?}"))
+            .with_label("cookie names must be HTTP tokens", span));
+    }
+
+    let attributes = match cookie.as_record() {
+        Ok(record) => record.clone(),
+        Err(_) => {
+            let mut record = Record::new();
+            record.push("value", cookie.clone());
+            record
+        }
+    };
+
+    let value = match attributes.get("value") {
+        Some(value) => cookie_string(value)?,
+        None => {
+            return Err(
+                LabeledError::new(format!("Missing value for cookie {name}"))
+                    .with_label("expected a value field", span),
+            )
+        }
+    };
+    let unquoted = value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(&value);
+    if !unquoted.bytes().all(is_cookie_octet) {
+        return Err(
+            LabeledError::new(format!("Invalid value for cookie {name}")).with_label(
+                "cookie values cannot contain spaces, quotes, commas, semicolons or backslashes",
+                span,
+            ),
+        );
+    }
+
+    let mut header = format!("{name}={value}");
+    let mut secure = false;
+    let mut same_site_none = false;
+    for (key, val) in attributes.iter() {
+        match key.as_str() {
+            "value" => {}
+            "path" => push_attribute(&mut header, "Path", &attribute_string(val)?),
+            "domain" => push_attribute(&mut header, "Domain", &attribute_string(val)?),
+            "max_age" => {
+                let seconds = match val {
+                    Value::Duration { val, .. } => val / 1_000_000_000,
+                    _ => val.as_int().map_err(|_| {
+                        LabeledError::new("Invalid cookie max_age")
+                            .with_label("expected an int (seconds) or a duration", val.span())
+                    })?,
+                };
+                push_attribute(&mut header, "Max-Age", &seconds.to_string());
+            }
+            "expires" => {
+                let expires = match val {
+                    Value::Date { val, .. } => val
+                        .naive_utc()
+                        .format("%a, %d %b %Y %H:%M:%S GMT")
+                        .to_string(),
+                    _ => attribute_string(val)?,
+                };
+                push_attribute(&mut header, "Expires", &expires);
+            }
+            "http_only" => {
+                if flag(val)? {
+                    header.push_str("; HttpOnly");
+                }
+            }
+            "secure" => {
+                secure = flag(val)?;
+                if secure {
+                    header.push_str("; Secure");
+                }
+            }
+            "same_site" => {
+                let same_site = attribute_string(val)?;
+                let same_site = match same_site.to_ascii_lowercase().as_str() {
+                    "strict" => "Strict",
+                    "lax" => "Lax",
+                    "none" => {
+                        same_site_none = true;
+                        "None"
+                    }
+                    _ => {
+                        return Err(LabeledError::new("Invalid cookie same_site")
+                            .with_label("expected strict, lax or none", val.span()))
+                    }
+                };
+                push_attribute(&mut header, "SameSite", same_site);
+            }
+            _ => {
+                return Err(LabeledError::new(format!("Unknown cookie field: {key}"))
+                    .with_label(
+                        "expected one of: value, path, domain, max_age, expires, http_only, secure, same_site",
+                        val.span(),
+                    ))
+            }
+        }
+    }
+
+    // Browsers drop SameSite=None cookies that aren't Secure
+    if same_site_none && !secure {
+        return Err(
+            LabeledError::new(format!("Cookie {name} has same_site none without secure"))
+                .with_label("set secure: true as well", span),
+        );
+    }
+
+    Ok(header)
+}
+
+fn push_attribute(header: &mut String, name: &str, value: &str) {
+    header.push_str("; ");
+    header.push_str(name);
+    header.push('=');
+    header.push_str(value);
+}
+
+fn cookie_string(value: &Value) -> Result<String, LabeledError> {
+    value.coerce_string().map_err(|_| {
+        LabeledError::new("Invalid cookie value").with_label("expected a string", value.span())
+    })
+}
+
+/// Attribute values may not contain control characters or `;`
+fn attribute_string(value: &Value) -> Result<String, LabeledError> {
+    let string = cookie_string(value)?;
+    if string.chars().any(|c| c.is_control() || c == ';') {
+        return Err(LabeledError::new("Invalid cookie attribute")
+            .with_label("cannot contain control characters or ';'", value.span()));
+    }
+    Ok(string)
+}
+
+fn flag(value: &Value) -> Result<bool, LabeledError> {
+    value.as_bool().map_err(|_| {
+        LabeledError::new("Invalid cookie flag").with_label("expected a bool", value.span())
+    })
+}
+
+/// `token` characters from RFC 9110
+fn is_token_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+/// `cookie-octet` from RFC 6265
+fn is_cookie_octet(byte: u8) -> bool {
+    matches!(byte, 0x21 | 0x23..=0x2B | 0x2D..=0x3A | 0x3C..=0x5B | 0x5D..=0x7E)
+}
@@ -0,0 +1,204 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{
+    LabeledError, PipelineData, Record, Signature, Span, Spanned, SyntaxShape, Type, Value,
+};
+
+use crate::HttpServePlugin;
+
+/// Content type of RFC 7807 problem details
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Error code prefix carrying the response status, as in `http::404`
+const STATUS_CODE_PREFIX: &str = "http::";
+
+pub struct HttpError;
+
+impl PluginCommand for HttpError {
+    type Plugin = HttpServePlugin;
+
+    fn name(&self) -> &str {
+        "http error"
+    }
+
+    fn description(&self) -> &str {
+        "Fail the current request with an HTTP error status"
+    }
+
+    fn extra_description(&self) -> &str {
+        r#"Raises an error that `http serve` answers with the given status instead of 500.
+The message is sent to the client as the problem details' detail:
+    http serve :3000 {|req| if $req.path != "/" { http error 404 "no such page" } }
+
+`error make` can do the same with an `http::<status>` code:
+    error make {msg: "bad id", code: "http::400"}
+Other fields of the record, such as an `http_status`, don't reach the server: the error
+only keeps its message, labels, help, url and code, so the status must go in the code."#
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .required("status", SyntaxShape::Int, "HTTP status, from 400 to 599")
+            .optional(
+                "msg",
+                SyntaxShape::String,
+                "Message for the client (the status' reason phrase by default)",
+            )
+            .input_output_type(Type::Any, Type::Nothing)
+    }
+
+    fn run(
+        &self,
+        _plugin: &HttpServePlugin,
+        _engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let status = call.req::<Spanned<i64>>(0)?;
+        let status_code = u16::try_from(status.item)
+            .ok()
+            .filter(|status| (400..=599).contains(status))
+            .ok_or_else(|| {
+                LabeledError::new("Invalid HTTP error status")
+                    .with_label("must be between 400 and 599", status.span)
+            })?;
+        let msg = call.opt::<String>(1)?.unwrap_or_else(|| {
+            tiny_http::StatusCode(status_code)
+                .default_reason_phrase()
+                .to_string()
+        });
+
+        Err(LabeledError::new(msg)
+            .with_code(format!("{STATUS_CODE_PREFIX}{status_code}"))
+            .with_label(format!("HTTP {status_code}"), call.head))
+    }
+}
+
+/// The response status an error asks for through an `http::<status>` code
+///
+/// Errors wrapping other errors are searched too, so that an `http error` raised
+/// inside `each` or a custom command still counts.
+pub fn error_status(error: &LabeledError) -> Option<u16> {
+    let status = error
+        .code
+        .as_deref()
+        .and_then(|code| code.strip_prefix(STATUS_CODE_PREFIX))
+        .and_then(|status| status.parse::<u16>().ok())
+        .filter(|status| (400..=599).contains(status));
+    status.or_else(|| {
+        error
+            .inner
+            .iter()
+            .find_map(|inner| error_status(&LabeledError::from_diagnostic(inner)))
+    })
+}
+
+/// Convert an error to the record passed to the `--on-error` closure
+///
+/// The record has `msg`, `status` (the response status the error maps to), `labels`
+/// (each with `text`, the `source` code it points at, and `start`/`end` offsets),
+/// and `help`, `code` and `url` when the error has them.
+pub fn error_to_value(
+    engine: &EngineInterface,
+    error: &LabeledError,
+    status: u16,
+    span: Span,
+) -> Value {
+    let mut record = Record::new();
+    record.push("msg", Value::string(&error.msg, span));
+    record.push("status", Value::int(status.into(), span));
+
+    let labels = error
+        .labels
+        .iter()
+        .map(|label| {
+            let mut record = Record::new();
+            record.push("text", Value::string(&label.text, span));
+            record.push(
+                "source",
+                span_source(engine, label.span).map_or_else(
+                    || Value::nothing(span),
+                    |source| Value::string(source, span),
+                ),
+            );
+            record.push("start", Value::int(label.span.start as i64, span));
+            record.push("end", Value::int(label.span.end as i64, span));
+            Value::record(record, span)
+        })
+        .collect();
+    record.push("labels", Value::list(labels, span));
+
+    for (name, field) in [
+        ("help", &error.help),
+        ("code", &error.code),
+        ("url", &error.url),
+    ] {
+        if let Some(field) = field {
+            record.push(name, Value::string(field, span));
+        }
+    }
+
+    Value::record(record, span)
+}
+
+/// Build an RFC 7807 `application/problem+json` response for an error
+///
+/// Only the status and its reason phrase are sent, plus the message of errors that
+/// chose their status (those are meant for the client). With `debug`, the message,
+/// help and labels (with the source they point at) are always included.
+pub fn problem_response(
+    engine: &EngineInterface,
+    error: &LabeledError,
+    status: u16,
+    debug: bool,
+) -> tiny_http::ResponseBox {
+    let mut problem = serde_json::Map::new();
+    problem.insert("type".into(), "about:blank".into());
+    problem.insert(
+        "title".into(),
+        tiny_http::StatusCode(status).default_reason_phrase().into(),
+    );
+    problem.insert("status".into(), status.into());
+
+    if debug || error_status(error).is_some() {
+        problem.insert("detail".into(), error.msg.clone().into());
+    }
+    if debug {
+        if let Some(help) = &error.help {
+            problem.insert("help".into(), help.clone().into());
+        }
+        if let Some(code) = &error.code {
+            problem.insert("code".into(), code.clone().into());
+        }
+        let labels = error
+            .labels
+            .iter()
+            .map(|label| {
+                serde_json::json!({
+                    "text": label.text,
+                    "source": span_source(engine, label.span),
+                })
+            })
+            .collect();
+        problem.insert("labels".into(), serde_json::Value::Array(labels));
+    }
+
+    let body = serde_json::Value::Object(problem).to_string();
+    tiny_http::Response::from_string(body)
+        .with_status_code(status)
+        .with_header(
+            tiny_http::Header::from_bytes(&b"Content-Type"[..], PROBLEM_JSON.as_bytes())
+                .expect("Invalid Content-Type header"),
+        )
+        .boxed()
+}
+
+/// The source code a span points at, if it is known to the engine
+fn span_source(engine: &EngineInterface, span: Span) -> Option<String> {
+    if span == Span::unknown() {
+        return None;
+    }
+    engine
+        .get_span_contents(span)
+        .ok()
+        .map(|contents| String::from_utf8_lossy(&contents).into_owned())
+}
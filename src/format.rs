@@ -0,0 +1,337 @@
+use nu_plugin::{EngineInterface, EvaluatedCall};
+use nu_protocol::{Config, LabeledError, PipelineData, Span, Value};
+
+use crate::json::{JsonOptions, StreamFormat};
+use crate::response::value_to_bytes;
+
+/// Media types of MessagePack, accepted in request bodies and Accept headers alike
+pub const MSGPACK_MEDIA_TYPES: &[&str] = &[
+    "application/msgpack",
+    "application/x-msgpack",
+    "application/vnd.msgpack",
+];
+
+/// A format records and lists can be sent in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Nuon,
+    Msgpack,
+    Csv,
+    Tsv,
+    Yaml,
+    Html,
+}
+
+impl Format {
+    /// All formats, in order of preference when the client rates them equally
+    const ALL: [Format; 7] = [
+        Format::Json,
+        Format::Nuon,
+        Format::Msgpack,
+        Format::Csv,
+        Format::Tsv,
+        Format::Yaml,
+        Format::Html,
+    ];
+
+    /// Media types the format is known by, the canonical one first
+    fn media_types(self) -> &'static [&'static str] {
+        match self {
+            Format::Json => &["application/json"],
+            Format::Nuon => &["application/x-nuon"],
+            Format::Msgpack => MSGPACK_MEDIA_TYPES,
+            Format::Csv => &["text/csv"],
+            Format::Tsv => &["text/tab-separated-values"],
+            Format::Yaml => &["application/yaml", "application/x-yaml", "text/yaml"],
+            Format::Html => &["text/html"],
+        }
+    }
+
+    /// Content-Type header value for a body in this format
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Nuon => "application/x-nuon",
+            Format::Msgpack => "application/msgpack",
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Tsv => "text/tab-separated-values; charset=utf-8",
+            Format::Yaml => "application/yaml",
+            Format::Html => "text/html; charset=utf-8",
+        }
+    }
+
+    /// The format named by a content type, e.g. the one the closure set in metadata
+    ///
+    /// Structured syntax suffixes count too: `application/problem+json` is JSON.
+    pub fn from_content_type(content_type: &str) -> Option<Format> {
+        let media_type = content_type.split(';').next()?.trim().to_ascii_lowercase();
+        if media_type.ends_with("+json") {
+            return Some(Format::Json);
+        }
+        if media_type.ends_with("+yaml") {
+            return Some(Format::Yaml);
+        }
+        Format::ALL
+            .into_iter()
+            .find(|format| format.media_types().contains(&media_type.as_str()))
+    }
+
+    /// Whether the value can be represented in this format
+    ///
+    /// CSV and TSV only hold tables: a record or a list of records.
+    fn supports(self, value: &Value) -> bool {
+        match self {
+            Format::Csv | Format::Tsv => match value {
+                Value::Record { .. } => true,
+                Value::List { vals, .. } => vals.iter().all(|v| matches!(v, Value::Record { .. })),
+                _ => false,
+            },
+            _ => true,
+        }
+    }
+
+    /// Serialize a value in this format
+    ///
+    /// JSON and HTML are written here; the other formats use Nushell's own `to` commands.
+    pub fn encode(
+        self,
+        engine: &EngineInterface,
+        value: Value,
+        json: JsonOptions,
+        span: Span,
+    ) -> Result<Vec<u8>, LabeledError> {
+        let command = match self {
+            Format::Json => return Ok(value_to_bytes(value, json)),
+            Format::Html => return Ok(value_to_html(&value).into_bytes()),
+            Format::Nuon => "to nuon",
+            Format::Msgpack => "to msgpack",
+            Format::Csv => "to csv",
+            Format::Tsv => "to tsv",
+            Format::Yaml => "to yaml",
+        };
+
+        let decl_id = engine.find_decl(command)?.ok_or_else(|| {
+            LabeledError::new(format!("Command not found: {command}"))
+                .with_label("needed to encode the response", span)
+        })?;
+        let output = engine.call_decl(
+            decl_id,
+            EvaluatedCall::new(span),
+            PipelineData::Value(value, None),
+            true,
+            false,
+        )?;
+        match output.into_value(span)? {
+            Value::String { val, .. } => Ok(val.into_bytes()),
+            Value::Binary { val, .. } => Ok(val),
+            other => Err(LabeledError::new(format!(
+                "`{command}` returned {} instead of a string or binary",
+                other.get_type()
+            ))),
+        }
+    }
+}
+
+/// Pick the format for a structured value from the request's Accept header
+///
+/// The format with the highest quality wins, ties going to the order of `Format::ALL`
+/// (so `*/*` gives JSON). Without an Accept header the answer is JSON. Returns `None`
+/// when the client accepts none of the formats that can hold the value.
+pub fn negotiate_format(request: &tiny_http::Request, value: &Value) -> Option<Format> {
+    let accept = accepted_ranges(request);
+    if accept.is_empty() {
+        return Some(Format::Json);
+    }
+
+    let mut best: Option<(Format, f32)> = None;
+    for format in Format::ALL {
+        if !format.supports(value) {
+            continue;
+        }
+        let q = quality(&accept, format);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((format, q));
+        }
+    }
+    best.map(|(format, _)| format)
+}
+
+/// Pick the framing of a ListStream from the request's Accept header
+///
+/// A JSON array is sent for `application/json`, NDJSON for `application/x-ndjson` or
+/// `application/jsonl`. The higher quality wins, the most specific range counting for
+/// each; when they are equal (no Accept header, or `*/*`), `default` is used. Returns
+/// `None` when the client accepts neither, so the stream has to be collected and sent
+/// in another format.
+pub fn negotiate_stream_format(
+    request: &tiny_http::Request,
+    default: StreamFormat,
+) -> Option<StreamFormat> {
+    let accept = accepted_ranges(request);
+    if accept.is_empty() {
+        return Some(default);
+    }
+
+    let array = media_quality(&accept, &["application/json"]);
+    let ndjson = media_quality(&accept, StreamFormat::NDJSON_TYPES);
+    if array <= 0.0 && ndjson <= 0.0 {
+        None
+    } else if ndjson > array {
+        Some(StreamFormat::Ndjson)
+    } else if array > ndjson {
+        Some(StreamFormat::JsonArray)
+    } else {
+        Some(default)
+    }
+}
+
+/// Content types that could have been negotiated for a value, for the 406 response
+pub fn available_content_types(value: &Value) -> Vec<&'static str> {
+    Format::ALL
+        .into_iter()
+        .filter(|format| format.supports(value))
+        .map(|format| format.media_types()[0])
+        .collect()
+}
+
+/// The media ranges of all the request's Accept headers
+fn accepted_ranges(request: &tiny_http::Request) -> Vec<(String, f32)> {
+    request
+        .headers()
+        .iter()
+        .filter(|h| h.field.equiv("Accept"))
+        .flat_map(|h| parse_accept(h.value.as_str()))
+        .collect()
+}
+
+/// Parse an Accept header into lowercase media ranges and their quality
+fn parse_accept(header: &str) -> Vec<(String, f32)> {
+    header
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let range = params.next()?.trim().to_ascii_lowercase();
+            if range.is_empty() {
+                return None;
+            }
+            let q = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((range, q))
+        })
+        .collect()
+}
+
+/// The quality the client gives a format, taken from the most specific matching range
+fn quality(accept: &[(String, f32)], format: Format) -> f32 {
+    media_quality(accept, format.media_types())
+}
+
+/// The quality the client gives any of these media types, from the most specific range
+fn media_quality(accept: &[(String, f32)], media_types: &[&str]) -> f32 {
+    // (specificity, quality): exact type > type/* > */*
+    let mut best: Option<(u8, f32)> = None;
+    for media_type in media_types {
+        let main_type = media_type.split('/').next().unwrap_or_default();
+        for (range, q) in accept {
+            let specificity = if range.as_str() == *media_type {
+                2
+            } else if range.strip_suffix("/*") == Some(main_type) {
+                1
+            } else if range == "*/*" {
+                0
+            } else {
+                continue;
+            };
+            if best.is_none_or(|(best_specificity, _)| specificity > best_specificity) {
+                best = Some((specificity, *q));
+            }
+        }
+    }
+    best.map_or(0.0, |(_, q)| q)
+}
+
+/// Render a value as an HTML page, records and tables as `<table>`s
+fn value_to_html(value: &Value) -> String {
+    let mut html =
+        String::from("<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"></head>\n<body>\n");
+    push_html(&mut html, value);
+    html.push_str("\n</body>\n</html>\n");
+    html
+}
+
+fn push_html(html: &mut String, value: &Value) {
+    match value {
+        Value::Record { val, .. } => {
+            html.push_str("<table>");
+            for (column, value) in val.iter() {
+                html.push_str("<tr><th>");
+                push_escaped(html, column);
+                html.push_str("</th><td>");
+                push_html(html, value);
+                html.push_str("</td></tr>");
+            }
+            html.push_str("</table>");
+        }
+        // A list of records is a table, with the union of their columns as header
+        Value::List { vals, .. }
+            if !vals.is_empty() && vals.iter().all(|v| matches!(v, Value::Record { .. })) =>
+        {
+            let mut columns: Vec<&String> = Vec::new();
+            for row in vals {
+                if let Value::Record { val, .. } = row {
+                    for column in val.columns() {
+                        if !columns.contains(&column) {
+                            columns.push(column);
+                        }
+                    }
+                }
+            }
+
+            html.push_str("<table><thead><tr>");
+            for column in &columns {
+                html.push_str("<th>");
+                push_escaped(html, column);
+                html.push_str("</th>");
+            }
+            html.push_str("</tr></thead><tbody>");
+            for row in vals {
+                html.push_str("<tr>");
+                for column in &columns {
+                    html.push_str("<td>");
+                    if let Some(value) = row.as_record().ok().and_then(|r| r.get(column.as_str())) {
+                        push_html(html, value);
+                    }
+                    html.push_str("</td>");
+                }
+                html.push_str("</tr>");
+            }
+            html.push_str("</tbody></table>");
+        }
+        Value::List { vals, .. } => {
+            html.push_str("<ul>");
+            for value in vals {
+                html.push_str("<li>");
+                push_html(html, value);
+                html.push_str("</li>");
+            }
+            html.push_str("</ul>");
+        }
+        _ => push_escaped(html, &value.to_expanded_string(", ", &Config::default())),
+    }
+}
+
+fn push_escaped(html: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c => html.push(c),
+        }
+    }
+}
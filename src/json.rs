@@ -0,0 +1,209 @@
+use base64::Engine;
+use nu_protocol::{ast::PathMember, Config, LabeledError, Range, Value};
+use std::ops::Bound;
+
+/// How durations and filesizes are written in JSON
+///
+/// Both are sent as numbers in the chosen unit: an integer when the value is a whole
+/// number of units, a float otherwise. The defaults (nanoseconds and bytes) match
+/// Nushell's `to json`.
+#[derive(Debug, Clone, Copy)]
+pub struct JsonOptions {
+    /// Nanoseconds per duration unit
+    pub duration_unit: i64,
+    /// Bytes per filesize unit
+    pub filesize_unit: i64,
+    /// Framing of streamed lists when neither the closure nor the client picks one
+    pub stream_format: StreamFormat,
+}
+
+impl Default for JsonOptions {
+    fn default() -> Self {
+        Self {
+            duration_unit: 1,
+            filesize_unit: 1,
+            stream_format: StreamFormat::JsonArray,
+        }
+    }
+}
+
+/// How the items of a ListStream are framed as JSON
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamFormat {
+    /// A single JSON array, written as items arrive: `[`, items separated by `,`, `]`
+    JsonArray,
+    /// One JSON value per line
+    Ndjson,
+}
+
+impl StreamFormat {
+    /// Media types for newline-delimited JSON
+    pub const NDJSON_TYPES: &'static [&'static str] = &[
+        "application/x-ndjson",
+        "application/ndjson",
+        "application/jsonl",
+        "application/x-jsonlines",
+    ];
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            StreamFormat::JsonArray => "application/json",
+            StreamFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    /// The framing named by a content type, e.g. the one the closure set in metadata
+    pub fn from_content_type(content_type: &str) -> Option<StreamFormat> {
+        let media_type = content_type.split(';').next()?.trim().to_ascii_lowercase();
+        if media_type == "application/json" || media_type.ends_with("+json") {
+            Some(StreamFormat::JsonArray)
+        } else if Self::NDJSON_TYPES.contains(&media_type.as_str()) {
+            Some(StreamFormat::Ndjson)
+        } else {
+            None
+        }
+    }
+}
+
+/// Nanoseconds in a duration unit, named as in Nushell duration literals
+pub fn duration_unit(name: &str) -> Option<i64> {
+    Some(match name {
+        "ns" => 1,
+        "us" | "µs" => 1_000,
+        "ms" => 1_000_000,
+        "sec" => 1_000_000_000,
+        "min" => 60 * 1_000_000_000,
+        "hr" => 60 * 60 * 1_000_000_000,
+        "day" => 24 * 60 * 60 * 1_000_000_000,
+        "wk" => 7 * 24 * 60 * 60 * 1_000_000_000,
+        _ => return None,
+    })
+}
+
+/// Bytes in a filesize unit, named as in Nushell filesize literals (case-insensitive)
+pub fn filesize_unit(name: &str) -> Option<i64> {
+    Some(match name.to_ascii_lowercase().as_str() {
+        "b" => 1,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "tb" => 1_000_000_000_000,
+        "pb" => 1_000_000_000_000_000,
+        "eb" => 1_000_000_000_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        "pib" => 1 << 50,
+        "eib" => 1 << 60,
+        _ => return None,
+    })
+}
+
+/// Convert Nu Value to serde_json::Value
+///
+/// Dates are RFC 3339 strings, binary is base64, ranges are objects with `start`, `end`
+/// (null when unbounded), `step` and `inclusive` fields, cell paths are lists of
+/// members, and errors are objects with `error`, `code`, `help` and `url` fields.
+/// Values without a JSON form (closures) are sent as Nushell displays them.
+pub fn value_to_json(value: &Value, options: JsonOptions) -> serde_json::Value {
+    match value {
+        Value::Nothing { .. } => serde_json::Value::Null,
+        Value::Bool { val, .. } => serde_json::Value::Bool(*val),
+        Value::Int { val, .. } => serde_json::Value::Number((*val).into()),
+        Value::Float { val, .. } => float_to_json(*val),
+        Value::String { val, .. } => serde_json::Value::String(val.clone()),
+        Value::Date { val, .. } => serde_json::Value::String(val.to_rfc3339()),
+        Value::Duration { val, .. } => number_in_unit(*val, options.duration_unit),
+        Value::Filesize { val, .. } => number_in_unit(val.get(), options.filesize_unit),
+        Value::Binary { val, .. } => {
+            serde_json::Value::String(base64::engine::general_purpose::STANDARD.encode(val))
+        }
+        Value::Range { val, .. } => range_to_json(val),
+        Value::List { vals, .. } => serde_json::Value::Array(
+            vals.iter()
+                .map(|item| value_to_json(item, options))
+                .collect(),
+        ),
+        Value::Record { val, .. } => {
+            let mut map = serde_json::Map::new();
+            for (k, v) in val.iter() {
+                map.insert(k.clone(), value_to_json(v, options));
+            }
+            serde_json::Value::Object(map)
+        }
+        Value::CellPath { val, .. } => serde_json::Value::Array(
+            val.members
+                .iter()
+                .map(|member| match member {
+                    PathMember::String { val, .. } => serde_json::Value::String(val.clone()),
+                    PathMember::Int { val, .. } => serde_json::Value::Number((*val).into()),
+                })
+                .collect(),
+        ),
+        Value::Error { error, .. } => error_to_json(&LabeledError::from_diagnostic(error.as_ref())),
+        Value::Custom { val, .. } => match val.to_base_value(value.span()) {
+            Ok(base) => value_to_json(&base, options),
+            Err(_) => serde_json::Value::String(val.type_name()),
+        },
+        _ => serde_json::Value::String(value.to_expanded_string(", ", &Config::default())),
+    }
+}
+
+fn float_to_json(val: f64) -> serde_json::Value {
+    serde_json::Number::from_f64(val)
+        .map(serde_json::Value::Number)
+        .unwrap_or(serde_json::Value::Null)
+}
+
+/// An integer when `val` is a whole number of units, a float otherwise
+fn number_in_unit(val: i64, unit: i64) -> serde_json::Value {
+    if val % unit == 0 {
+        serde_json::Value::Number((val / unit).into())
+    } else {
+        float_to_json(val as f64 / unit as f64)
+    }
+}
+
+/// Describe a range by its bounds and step, without listing its values
+fn range_to_json(range: &Range) -> serde_json::Value {
+    let (start, end, step) = match range {
+        Range::IntRange(range) => (
+            serde_json::Value::from(range.start()),
+            range.end().map(serde_json::Value::from),
+            serde_json::Value::from(range.step()),
+        ),
+        Range::FloatRange(range) => (
+            float_to_json(range.start()),
+            range.end().map(float_to_json),
+            float_to_json(range.step()),
+        ),
+    };
+    let (end, inclusive) = match end {
+        Bound::Included(end) => (end, true),
+        Bound::Excluded(end) => (end, false),
+        Bound::Unbounded => (serde_json::Value::Null, true),
+    };
+
+    let mut map = serde_json::Map::new();
+    map.insert("start".into(), start);
+    map.insert("end".into(), end);
+    map.insert("step".into(), step);
+    map.insert("inclusive".into(), inclusive.into());
+    serde_json::Value::Object(map)
+}
+
+fn error_to_json(error: &LabeledError) -> serde_json::Value {
+    let mut map = serde_json::Map::new();
+    map.insert("error".into(), error.msg.clone().into());
+    for (key, field) in [
+        ("code", &error.code),
+        ("help", &error.help),
+        ("url", &error.url),
+    ] {
+        if let Some(field) = field {
+            map.insert(key.into(), field.clone().into());
+        }
+    }
+    serde_json::Value::Object(map)
+}
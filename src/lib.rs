@@ -1,7 +1,18 @@
 use nu_plugin::{Plugin, PluginCommand};
 
+mod conditional;
+mod cookie;
+mod error;
+mod format;
+mod json;
+mod multipart;
 mod plugin;
+mod redirect;
+mod request;
+mod response;
 mod serve;
+mod sse;
+mod websocket;
 
 pub use plugin::HttpServePlugin;
 
@@ -11,6 +22,11 @@ impl Plugin for HttpServePlugin {
     }
 
     fn commands(&self) -> Vec<Box<dyn PluginCommand<Plugin = Self>>> {
-        vec![Box::new(crate::serve::HttpServe)]
+        vec![
+            Box::new(crate::serve::HttpServe),
+            Box::new(crate::redirect::HttpRedirect),
+            Box::new(crate::multipart::HttpMultipart),
+            Box::new(crate::error::HttpError),
+        ]
     }
 }
@@ -0,0 +1,225 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{
+    engine::Closure, ByteStream, ByteStreamType, IntoSpanned, LabeledError, ListStream,
+    PipelineData, Record, Signature, Span, Spanned, SyntaxShape, Type, Value,
+};
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use tiny_http::{Multipart, MultipartPart};
+
+use crate::request::push_or_append;
+use crate::HttpServePlugin;
+
+type SharedMultipart = Arc<Mutex<Multipart<Box<dyn Read + Send>>>>;
+
+pub struct HttpMultipart;
+
+impl PluginCommand for HttpMultipart {
+    type Plugin = HttpServePlugin;
+
+    fn name(&self) -> &str {
+        "http multipart"
+    }
+
+    fn description(&self) -> &str {
+        "Parse a multipart/form-data request body into its parts"
+    }
+
+    fn extra_description(&self) -> &str {
+        r#"The body is parsed as it streams in, using the boundary from its content type.
+
+Without a closure, each part is a record with name, filename, content_type, headers
+and content. The filename is taken from filename* (RFC 5987) when the part has one.
+Parts without a filename have their content as a string, files as binary:
+    http serve :3000 {|req| http multipart | where filename == null }
+
+With a closure, the closure runs once per part with the part record as its argument and
+the part's content as a byte stream on $in, so large files are never held in memory:
+    http serve :3000 {|req| http multipart {|part| $in | save $"uploads/($part.filename)" } }"#
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .optional(
+                "closure",
+                SyntaxShape::Closure(Some(vec![SyntaxShape::Record(vec![])])),
+                "The closure to evaluate for each part, with the part's content as $in",
+            )
+            .input_output_types(vec![
+                (Type::Binary, Type::List(Box::new(Type::Any))),
+                (Type::String, Type::List(Box::new(Type::Any))),
+            ])
+    }
+
+    fn run(
+        &self,
+        _plugin: &HttpServePlugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let span = call.head;
+        let closure = call
+            .opt::<Value>(0)?
+            .map(|value| value.into_closure().map(|c| c.into_spanned(span)))
+            .transpose()?;
+
+        let content_type = input
+            .metadata()
+            .and_then(|meta| meta.content_type)
+            .ok_or_else(|| {
+                LabeledError::new("Missing content type")
+                    .with_label("the input has no multipart/form-data content type", span)
+            })?;
+        let reader: Box<dyn Read + Send> = match input {
+            PipelineData::ByteStream(stream, _) => match stream.reader() {
+                Some(reader) => Box::new(reader),
+                None => Box::new(std::io::empty()),
+            },
+            PipelineData::Value(Value::Binary { val, .. }, _) => {
+                Box::new(std::io::Cursor::new(val))
+            }
+            PipelineData::Value(Value::String { val, .. }, _) => {
+                Box::new(std::io::Cursor::new(val.into_bytes()))
+            }
+            _ => {
+                return Err(LabeledError::new("Invalid input")
+                    .with_label("expected the request body as $in", span))
+            }
+        };
+        let multipart = Multipart::from_content_type(reader, &content_type).ok_or_else(|| {
+            LabeledError::new("Not a multipart body").with_label(
+                format!(
+                    "content type is {content_type}, expected multipart/form-data with a boundary"
+                ),
+                span,
+            )
+        })?;
+        let multipart = Arc::new(Mutex::new(multipart));
+
+        match closure {
+            Some(closure) => run_closure(engine, span, &closure, multipart),
+            None => Ok(PipelineData::ListStream(
+                ListStream::new(Parts { multipart, span }, span, engine.signals().clone()),
+                None,
+            )),
+        }
+    }
+}
+
+/// Evaluate the closure for each part, streaming the part's content as $in
+fn run_closure(
+    engine: &EngineInterface,
+    span: Span,
+    closure: &Spanned<Closure>,
+    multipart: SharedMultipart,
+) -> Result<PipelineData, LabeledError> {
+    let mut results = Vec::new();
+    while let Some(part) = next_part(&multipart, span)? {
+        let content = ByteStream::read(
+            PartReader(multipart.clone()),
+            span,
+            engine.signals().clone(),
+            ByteStreamType::Unknown,
+        );
+        let input = PipelineData::ByteStream(content, None);
+        let result = engine.eval_closure_with_stream(
+            closure,
+            vec![Value::record(part_to_record(&part, span), span)],
+            input,
+            true,
+            false,
+        )?;
+        results.push(result.into_value(span)?);
+    }
+
+    Ok(PipelineData::Value(Value::list(results, span), None))
+}
+
+/// Iterator over the parts, reading each part's content in full
+struct Parts {
+    multipart: SharedMultipart,
+    span: Span,
+}
+
+impl Iterator for Parts {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        let part = match next_part(&self.multipart, self.span) {
+            Ok(part) => part?,
+            Err(err) => return Some(Value::error(err.into(), self.span)),
+        };
+
+        let mut content = Vec::new();
+        if let Err(err) = PartReader(self.multipart.clone()).read_to_end(&mut content) {
+            return Some(Value::error(
+                multipart_error(err, self.span).into(),
+                self.span,
+            ));
+        }
+
+        let content = match part.filename {
+            Some(_) => Value::binary(content, self.span),
+            None => match String::from_utf8(content) {
+                Ok(text) => Value::string(text, self.span),
+                Err(err) => Value::binary(err.into_bytes(), self.span),
+            },
+        };
+        let mut record = part_to_record(&part, self.span);
+        record.push("content", content);
+        Some(Value::record(record, self.span))
+    }
+}
+
+/// `Read` adapter over the content of the current part
+struct PartReader(SharedMultipart);
+
+impl Read for PartReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0
+            .lock()
+            .map_err(|_| std::io::Error::other("multipart reader poisoned"))?
+            .read(buf)
+    }
+}
+
+fn next_part(
+    multipart: &SharedMultipart,
+    span: Span,
+) -> Result<Option<MultipartPart>, LabeledError> {
+    multipart
+        .lock()
+        .map_err(|_| LabeledError::new("multipart reader poisoned"))?
+        .next_part()
+        .map_err(|err| multipart_error(err, span))
+}
+
+fn multipart_error(err: std::io::Error, span: Span) -> LabeledError {
+    LabeledError::new("Invalid multipart body").with_label(err.to_string(), span)
+}
+
+/// Convert a part's headers to a record (without its content)
+fn part_to_record(part: &MultipartPart, span: Span) -> Record {
+    let optional = |value: Option<&str>| match value {
+        Some(value) => Value::string(value, span),
+        None => Value::nothing(span),
+    };
+
+    let mut headers = Record::new();
+    for (name, value) in &part.headers {
+        push_or_append(
+            &mut headers,
+            name.to_ascii_lowercase(),
+            Value::string(value, span),
+            span,
+        );
+    }
+
+    let mut record = Record::new();
+    record.push("name", optional(part.name.as_deref()));
+    record.push("filename", optional(part.filename.as_deref()));
+    record.push("content_type", optional(part.content_type()));
+    record.push("headers", Value::record(headers, span));
+    record
+}
@@ -0,0 +1,167 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{
+    record, LabeledError, PipelineData, PipelineMetadata, Signature, Spanned, SyntaxShape, Type,
+    Value,
+};
+
+use crate::response::RESPONSE_META_KEY;
+use crate::HttpServePlugin;
+
+/// Statuses `http redirect` accepts
+const REDIRECT_STATUSES: [u16; 5] = [301, 302, 303, 307, 308];
+
+pub struct HttpRedirect;
+
+impl PluginCommand for HttpRedirect {
+    type Plugin = HttpServePlugin;
+
+    fn name(&self) -> &str {
+        "http redirect"
+    }
+
+    fn description(&self) -> &str {
+        "Answer the current request with a redirect"
+    }
+
+    fn extra_description(&self) -> &str {
+        r#"Returns an empty response whose 'http.response' metadata holds the status and
+the redirect target. `http serve` sends it as the Location header, resolving relative
+URLs against the request's scheme and Host:
+    http serve :3000 {|req| if $req.path == "/old" { http redirect /new --status 301 } }"#
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .required(
+                "url",
+                SyntaxShape::String,
+                "Where to redirect to, absolute or relative to the request",
+            )
+            .named(
+                "status",
+                SyntaxShape::Int,
+                "Redirect status: 301, 302 (default), 303, 307 or 308",
+                Some('s'),
+            )
+            .input_output_type(Type::Any, Type::Nothing)
+    }
+
+    fn run(
+        &self,
+        _plugin: &HttpServePlugin,
+        _engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let span = call.head;
+        let url = call.req::<String>(0)?;
+        let status = match call.get_flag::<Spanned<i64>>("status")? {
+            Some(status) => u16::try_from(status.item)
+                .ok()
+                .filter(|code| REDIRECT_STATUSES.contains(code))
+                .ok_or_else(|| {
+                    LabeledError::new("Invalid redirect status")
+                        .with_label("expected one of: 301, 302, 303, 307, 308", status.span)
+                })?,
+            None => 302,
+        };
+
+        let mut meta = PipelineMetadata::default();
+        meta.custom.push(
+            RESPONSE_META_KEY,
+            Value::record(
+                record! {
+                    "status" => Value::int(status.into(), span),
+                    "redirect" => Value::string(url, span),
+                },
+                span,
+            ),
+        );
+        Ok(PipelineData::Value(Value::nothing(span), Some(meta)))
+    }
+}
+
+/// Resolve a redirect target against the request's URL
+///
+/// Absolute URLs are kept, others are resolved against the request's scheme, Host
+/// and path. Without a Host header the target is sent as given, which clients resolve
+/// themselves.
+pub fn resolve_location(location: &str, request: &tiny_http::Request) -> String {
+    if has_scheme(location) {
+        return location.to_string();
+    }
+    let Some(host) = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Host"))
+        .map(|h| h.value.as_str().trim())
+        .filter(|host| !host.is_empty())
+    else {
+        return location.to_string();
+    };
+    let scheme = if request.secure() { "https" } else { "http" };
+
+    if location.starts_with("//") {
+        return format!("{scheme}:{location}");
+    }
+
+    let request_url = request.url();
+    let path_end = request_url.find(['?', '#']).unwrap_or(request_url.len());
+    let request_path = &request_url[..path_end];
+
+    let path = if location.starts_with('/') {
+        location.to_string()
+    } else if location.is_empty() || location.starts_with('#') {
+        format!("{request_url}{location}")
+    } else if location.starts_with('?') {
+        format!("{request_path}{location}")
+    } else {
+        // Relative to the request path's directory
+        let directory = &request_path[..request_path.rfind('/').map_or(0, |i| i + 1)];
+        let directory = if directory.is_empty() { "/" } else { directory };
+        format!("{directory}{location}")
+    };
+
+    format!("{scheme}://{host}{}", remove_dot_segments(&path))
+}
+
+/// Whether a URL starts with a scheme such as `https:`
+fn has_scheme(url: &str) -> bool {
+    match url.split_once(':') {
+        Some((scheme, _)) => {
+            scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+        }
+        None => false,
+    }
+}
+
+/// Resolve `.` and `..` segments of a path, leaving its query and fragment alone
+fn remove_dot_segments(path: &str) -> String {
+    let end = path.find(['?', '#']).unwrap_or(path.len());
+    let (path, rest) = path.split_at(end);
+
+    let mut segments: Vec<&str> = Vec::new();
+    let mut parts = path.split('/').skip(1).peekable();
+    while let Some(segment) = parts.next() {
+        let last = parts.peek().is_none();
+        match segment {
+            "." => {
+                if last {
+                    segments.push("");
+                }
+            }
+            ".." => {
+                segments.pop();
+                if last {
+                    segments.push("");
+                }
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    format!("/{}{rest}", segments.join("/"))
+}
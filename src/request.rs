@@ -0,0 +1,223 @@
+use nu_protocol::{PipelineData, Record, Span, Value};
+
+use crate::cookie::cookies_to_value;
+use crate::format::MSGPACK_MEDIA_TYPES;
+
+/// Convert tiny_http::Request to Nu Value (Record)
+///
+/// `path` is percent-decoded and stripped of the query string; `uri` is the request
+/// target exactly as the client sent it.
+pub fn request_to_value(request: &tiny_http::Request, span: Span) -> Value {
+    let mut record = Record::new();
+
+    // Method
+    record.push("method", Value::string(request.method().to_string(), span));
+
+    // Path/URL
+    let uri = request.url();
+    let (raw_path, query) = match uri.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (uri, ""),
+    };
+    record.push("path", Value::string(percent_decode(raw_path), span));
+    record.push("raw_path", Value::string(raw_path, span));
+    record.push("uri", Value::string(uri, span));
+
+    // Headers
+    record.push("headers", headers_to_value(request.headers(), span));
+
+    // Query parameters
+    record.push("query", query_to_value(query, span));
+
+    // Cookies
+    record.push("cookies", cookies_to_value(request.headers(), span));
+
+    // Remote address (None for Unix sockets)
+    if let Some(addr) = request.remote_addr() {
+        record.push("remote_addr", Value::string(addr.to_string(), span));
+    }
+
+    Value::record(record, span)
+}
+
+/// Convert request headers to a record keyed by lowercase header name
+///
+/// A header sent more than once becomes a list of its values, in the order received.
+fn headers_to_value(headers: &[tiny_http::Header], span: Span) -> Value {
+    let mut record = Record::new();
+    for header in headers {
+        let name = header.field.to_string().to_ascii_lowercase();
+        let value = Value::string(header.value.to_string(), span);
+        push_or_append(&mut record, name, value, span);
+    }
+    Value::record(record, span)
+}
+
+/// Parse an `application/x-www-form-urlencoded` query string into a record
+///
+/// Keys and values are percent-decoded with `+` as a space. A key without `=`
+/// (`?debug`) is `true`, and a key given more than once becomes a list.
+pub fn query_to_value(query: &str, span: Span) -> Value {
+    let mut record = Record::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = match pair.split_once('=') {
+            Some((key, value)) => (key, Value::string(form_decode(value), span)),
+            None => (pair, Value::bool(true, span)),
+        };
+        push_or_append(&mut record, form_decode(key), value, span);
+    }
+    Value::record(record, span)
+}
+
+/// Decode a request body by its Content-Type
+///
+/// JSON (including `+json` types), form, `text/*` and msgpack bodies are read in full
+/// and parsed into a value. Other bodies are passed through as a stream. The error is
+/// a message meant for the client.
+pub fn decode_body(input: PipelineData, span: Span) -> Result<PipelineData, String> {
+    let PipelineData::ByteStream(stream, Some(meta)) = input else {
+        return Ok(input);
+    };
+    let Some(media_type) = meta.content_type.as_deref().map(media_type) else {
+        return Ok(PipelineData::ByteStream(stream, Some(meta)));
+    };
+
+    let value = if media_type == "application/json" || media_type.ends_with("+json") {
+        let body = stream.into_bytes().map_err(|e| e.to_string())?;
+        let json: serde_json::Value =
+            serde_json::from_slice(&body).map_err(|e| format!("Invalid JSON body: {}", e))?;
+        json_to_value(json, span)
+    } else if media_type == "application/x-www-form-urlencoded" {
+        let body = stream.into_bytes().map_err(|e| e.to_string())?;
+        let body = String::from_utf8(body).map_err(|e| format!("Invalid form body: {}", e))?;
+        query_to_value(&body, span)
+    } else if media_type.starts_with("text/") {
+        let body = stream.into_bytes().map_err(|e| e.to_string())?;
+        let body = String::from_utf8(body).map_err(|e| format!("Invalid text body: {}", e))?;
+        Value::string(body, span)
+    } else if MSGPACK_MEDIA_TYPES.contains(&media_type.as_str()) {
+        let body = stream.into_bytes().map_err(|e| e.to_string())?;
+        let msgpack = rmpv::decode::read_value(&mut &body[..])
+            .map_err(|e| format!("Invalid msgpack body: {}", e))?;
+        msgpack_to_value(msgpack, span)
+    } else {
+        return Ok(PipelineData::ByteStream(stream, Some(meta)));
+    };
+
+    Ok(PipelineData::Value(value, None))
+}
+
+/// The lowercase media type of a Content-Type, without parameters
+fn media_type(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// Convert serde_json::Value to Nu Value
+fn json_to_value(json: serde_json::Value, span: Span) -> Value {
+    match json {
+        serde_json::Value::Null => Value::nothing(span),
+        serde_json::Value::Bool(val) => Value::bool(val, span),
+        serde_json::Value::Number(val) => match val.as_i64() {
+            Some(int) => Value::int(int, span),
+            None => Value::float(val.as_f64().unwrap_or(f64::NAN), span),
+        },
+        serde_json::Value::String(val) => Value::string(val, span),
+        serde_json::Value::Array(vals) => Value::list(
+            vals.into_iter().map(|v| json_to_value(v, span)).collect(),
+            span,
+        ),
+        serde_json::Value::Object(map) => Value::record(
+            map.into_iter()
+                .map(|(k, v)| (k, json_to_value(v, span)))
+                .collect(),
+            span,
+        ),
+    }
+}
+
+/// Convert a decoded msgpack value to Nu Value
+///
+/// Map keys that are not strings are converted to their display form. Strings that are
+/// not valid UTF-8 and extension types are passed through as binary.
+fn msgpack_to_value(msgpack: rmpv::Value, span: Span) -> Value {
+    match msgpack {
+        rmpv::Value::Nil => Value::nothing(span),
+        rmpv::Value::Boolean(val) => Value::bool(val, span),
+        rmpv::Value::Integer(val) => match val.as_i64() {
+            Some(int) => Value::int(int, span),
+            None => Value::float(val.as_f64().unwrap_or(f64::NAN), span),
+        },
+        rmpv::Value::F32(val) => Value::float(val.into(), span),
+        rmpv::Value::F64(val) => Value::float(val, span),
+        rmpv::Value::String(val) if val.is_str() => {
+            Value::string(val.into_str().unwrap_or_default(), span)
+        }
+        rmpv::Value::String(val) => Value::binary(val.into_bytes(), span),
+        rmpv::Value::Binary(val) => Value::binary(val, span),
+        rmpv::Value::Array(vals) => Value::list(
+            vals.into_iter()
+                .map(|v| msgpack_to_value(v, span))
+                .collect(),
+            span,
+        ),
+        rmpv::Value::Map(entries) => Value::record(
+            entries
+                .into_iter()
+                .map(|(k, v)| {
+                    let key = match k {
+                        rmpv::Value::String(key) => key.into_str().unwrap_or_default(),
+                        key => key.to_string(),
+                    };
+                    (key, msgpack_to_value(v, span))
+                })
+                .collect(),
+            span,
+        ),
+        rmpv::Value::Ext(_, data) => Value::binary(data, span),
+    }
+}
+
+/// Add a field to the record, turning it into a list if the field already exists
+pub fn push_or_append(record: &mut Record, name: String, value: Value, span: Span) {
+    match record.get_mut(&name) {
+        Some(Value::List { vals, .. }) => vals.push(value),
+        Some(existing) => {
+            let first = std::mem::replace(existing, Value::nothing(span));
+            *existing = Value::list(vec![first, value], span);
+        }
+        None => record.push(name, value),
+    }
+}
+
+/// Percent-decode a form component, where `+` stands for a space
+fn form_decode(input: &str) -> String {
+    percent_decode(&input.replace('+', " "))
+}
+
+/// Decode `%XX` escapes; malformed escapes are kept as-is and invalid UTF-8 is replaced
+fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(high), Some(low)) = (hex_digit(bytes[i + 1]), hex_digit(bytes[i + 2])) {
+                decoded.push(high << 4 | low);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
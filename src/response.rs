@@ -0,0 +1,640 @@
+use nu_plugin::EngineInterface;
+use nu_protocol::{record, LabeledError, ListStream, PipelineData, PipelineMetadata, Span, Value};
+use std::io::{Cursor, Read};
+use std::time::SystemTime;
+
+use crate::conditional::{
+    body_etag, etag_from_value, evaluate_preconditions, last_modified_from_value, Precondition,
+};
+use crate::cookie::set_cookies_from_value;
+use crate::format::{available_content_types, negotiate_format, negotiate_stream_format, Format};
+use crate::json::{value_to_json, JsonOptions, StreamFormat};
+use crate::redirect::resolve_location;
+use crate::sse::{accepts_event_stream, SseReader, EVENT_STREAM};
+
+/// Metadata key the closure uses to describe the response
+///
+/// ```nu
+/// "not found" | metadata set --merge {'http.response': {status: 404}}
+/// "ok" | metadata set --merge {'http.response': {cookies: {session: {value: "abc", http_only: true}}}}
+/// null | metadata set --merge {'http.response': {status: 303, redirect: "/done"}}
+/// ```
+pub const RESPONSE_META_KEY: &str = "http.response";
+
+/// Status and headers requested by the closure through pipeline metadata
+#[derive(Debug, Default, Clone)]
+pub struct ResponseMeta {
+    pub status: Option<u16>,
+    pub headers: Vec<(String, String)>,
+    /// Serialized `Set-Cookie` header values
+    pub cookies: Vec<String>,
+    /// Quoted entity tag, possibly weak (`W/"..."`)
+    pub etag: Option<String>,
+    pub last_modified: Option<SystemTime>,
+    /// Target of a redirect, sent as the Location header once resolved
+    pub redirect: Option<String>,
+}
+
+impl ResponseMeta {
+    /// Read the `http.response` record from pipeline metadata, if any
+    ///
+    /// Header values may be a single value or a list, in which case the header
+    /// is repeated once per item (e.g. several `Set-Cookie` headers).
+    pub fn from_metadata(meta: Option<&PipelineMetadata>) -> Result<Self, LabeledError> {
+        let Some(value) = meta.and_then(|m| m.custom.get(RESPONSE_META_KEY)) else {
+            return Ok(Self::default());
+        };
+        let span = value.span();
+        let record = value.as_record().map_err(|_| {
+            LabeledError::new(format!("Invalid {RESPONSE_META_KEY} metadata"))
+                .with_label("expected a record", span)
+        })?;
+
+        let mut response_meta = Self::default();
+        for (key, val) in record.iter() {
+            match key.as_str() {
+                "status" => response_meta.status = Some(status_from_value(val)?),
+                "headers" => response_meta.headers = headers_from_value(val)?,
+                "cookies" => response_meta.cookies = set_cookies_from_value(val)?,
+                "etag" => response_meta.etag = Some(etag_from_value(val)?),
+                "last_modified" => {
+                    response_meta.last_modified = Some(last_modified_from_value(val)?)
+                }
+                "redirect" => {
+                    response_meta.redirect = Some(val.coerce_string().map_err(|_| {
+                        LabeledError::new("Invalid redirect")
+                            .with_label("expected a string", val.span())
+                    })?)
+                }
+                _ => {
+                    return Err(LabeledError::new(format!(
+                        "Unknown {RESPONSE_META_KEY} field: {key}"
+                    ))
+                    .with_label(
+                        "expected one of: status, headers, cookies, etag, last_modified, redirect",
+                        val.span(),
+                    ))
+                }
+            }
+        }
+
+        // Validators given as plain headers take part in conditional requests too
+        for (name, value) in std::mem::take(&mut response_meta.headers) {
+            if name.eq_ignore_ascii_case("etag") && response_meta.etag.is_none() {
+                response_meta.etag = Some(value);
+            } else if name.eq_ignore_ascii_case("last-modified")
+                && response_meta.last_modified.is_none()
+                && httpdate::parse_http_date(&value).is_ok()
+            {
+                response_meta.last_modified = httpdate::parse_http_date(&value).ok();
+            } else {
+                response_meta.headers.push((name, value));
+            }
+        }
+
+        Ok(response_meta)
+    }
+
+    /// Apply the requested status and headers on top of a response
+    pub fn apply<R: Read>(
+        self,
+        mut response: tiny_http::Response<R>,
+    ) -> Result<tiny_http::Response<R>, LabeledError> {
+        if let Some(status) = self.status {
+            response = response.with_status_code(status);
+        }
+        for (name, value) in self.headers {
+            let header = tiny_http::Header::from_bytes(name.as_bytes(), value.as_bytes())
+                .map_err(|_| LabeledError::new(format!("Invalid response header: {name}")))?;
+            response.add_header(header);
+        }
+        if let Some(etag) = self.etag {
+            let header = tiny_http::Header::from_bytes(&b"ETag"[..], etag.as_bytes())
+                .map_err(|_| LabeledError::new(format!("Invalid ETag header: {etag}")))?;
+            response.add_header(header);
+        }
+        if let Some(last_modified) = self.last_modified {
+            let date = httpdate::fmt_http_date(last_modified);
+            let header = tiny_http::Header::from_bytes(&b"Last-Modified"[..], date.as_bytes())
+                .map_err(|_| LabeledError::new("Invalid Last-Modified header"))?;
+            response.add_header(header);
+        }
+        for cookie in self.cookies {
+            let header = tiny_http::Header::from_bytes(&b"Set-Cookie"[..], cookie.as_bytes())
+                .map_err(|_| LabeledError::new(format!("Invalid Set-Cookie header: {cookie}")))?;
+            response.add_header(header);
+        }
+        Ok(response)
+    }
+}
+
+/// Set the response status in `http.response` metadata, unless it is already set
+pub fn with_default_status(pipeline_data: PipelineData, status: u16, span: Span) -> PipelineData {
+    let mut meta = pipeline_data.metadata().unwrap_or_default();
+    match meta.custom.get_mut(RESPONSE_META_KEY) {
+        Some(Value::Record { val, .. }) => {
+            if !val.contains("status") {
+                val.to_mut().push("status", Value::int(status.into(), span));
+            }
+        }
+        // Reported when the response is built
+        Some(_) => {}
+        None => meta.custom.push(
+            RESPONSE_META_KEY,
+            Value::record(
+                record! { "status" => Value::int(status.into(), span) },
+                span,
+            ),
+        ),
+    }
+
+    // Empty pipelines can't carry metadata
+    match pipeline_data {
+        PipelineData::Empty => PipelineData::Value(Value::nothing(span), Some(meta)),
+        pipeline_data => pipeline_data.set_metadata(Some(meta)),
+    }
+}
+
+/// Convert a status value to a status code, rejecting anything outside 100-999
+fn status_from_value(value: &Value) -> Result<u16, LabeledError> {
+    let span = value.span();
+    let status = value.as_int().map_err(|_| {
+        LabeledError::new("Invalid response status").with_label("expected an int", span)
+    })?;
+    u16::try_from(status)
+        .ok()
+        .filter(|status| (100..=999).contains(status))
+        .ok_or_else(|| {
+            LabeledError::new("Invalid response status")
+                .with_label("must be between 100 and 999", span)
+        })
+}
+
+/// Convert a headers record to (name, value) pairs, expanding list values
+fn headers_from_value(value: &Value) -> Result<Vec<(String, String)>, LabeledError> {
+    let record = value.as_record().map_err(|_| {
+        LabeledError::new("Invalid response headers").with_label("expected a record", value.span())
+    })?;
+
+    let mut headers = Vec::new();
+    for (name, val) in record.iter() {
+        match val {
+            Value::List { vals, .. } => {
+                for item in vals {
+                    headers.push((name.clone(), header_value_to_string(item)?));
+                }
+            }
+            _ => headers.push((name.clone(), header_value_to_string(val)?)),
+        }
+    }
+    Ok(headers)
+}
+
+fn header_value_to_string(value: &Value) -> Result<String, LabeledError> {
+    value.coerce_string().map_err(|_| {
+        LabeledError::new("Invalid response header value")
+            .with_label("expected a string", value.span())
+    })
+}
+
+/// Get the metadata attached to pipeline data without consuming it
+fn pipeline_metadata(pipeline_data: &PipelineData) -> Option<&PipelineMetadata> {
+    match pipeline_data {
+        PipelineData::Empty => None,
+        PipelineData::Value(_, meta)
+        | PipelineData::ListStream(_, meta)
+        | PipelineData::ByteStream(_, meta) => meta.as_ref(),
+    }
+}
+
+/// Convert PipelineData to tiny_http::Response, honoring `http.response` metadata
+///
+/// Requests that can't be answered with the closure's output (406 Not Acceptable, 412
+/// Precondition Failed) get the response `reject` builds for an error with that status.
+/// With `compression`, a 304 or 412 carries the `Vary` header and weak `ETag` the
+/// compressed response would have had.
+pub fn pipeline_data_to_response(
+    engine: &EngineInterface,
+    pipeline_data: PipelineData,
+    request: &tiny_http::Request,
+    json: JsonOptions,
+    compression: Option<&tiny_http::CompressionConfig>,
+    span: Span,
+    reject: &dyn Fn(LabeledError) -> tiny_http::ResponseBox,
+) -> Result<tiny_http::ResponseBox, LabeledError> {
+    let mut response_meta = ResponseMeta::from_metadata(pipeline_metadata(&pipeline_data))?;
+
+    if let Some(location) = response_meta.redirect.take() {
+        let location = resolve_location(&location, request);
+        response_meta
+            .headers
+            .push(("Location".to_string(), location));
+        response_meta.status.get_or_insert(302);
+    }
+
+    // A list stream is written item by item as a JSON array or NDJSON; when it has to
+    // be sent in another format (e.g. CSV), it is collected and sent like a list
+    let mut stream_format = None;
+    let pipeline_data = match pipeline_data {
+        PipelineData::ListStream(stream, meta) if !wants_event_stream(&meta, request) => {
+            match list_stream_format(&meta, request, json, &mut response_meta)? {
+                Some(format) => {
+                    stream_format = Some(format);
+                    PipelineData::ListStream(stream, meta)
+                }
+                None => PipelineData::Value(stream.into_value(), meta),
+            }
+        }
+        pipeline_data => pipeline_data,
+    };
+
+    // Records and lists are sent in the format the closure set as content type,
+    // otherwise in the one the client accepts
+    let format = match &pipeline_data {
+        PipelineData::Value(value @ (Value::Record { .. } | Value::List { .. }), meta) => {
+            match meta.as_ref().and_then(|m| m.content_type.as_deref()) {
+                Some(content_type) => {
+                    Some(Format::from_content_type(content_type).unwrap_or(Format::Json))
+                }
+                None => {
+                    response_meta
+                        .headers
+                        .push(("Vary".to_string(), "Accept".to_string()));
+                    match negotiate_format(request, value) {
+                        Some(format) => Some(format),
+                        None => return Ok(not_acceptable(value, reject)),
+                    }
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let body = body_to_response(
+        engine,
+        pipeline_data,
+        request,
+        format,
+        stream_format,
+        json,
+        &mut response_meta,
+        span,
+    )?;
+
+    // Conditional requests only apply to responses that would otherwise succeed; the
+    // closure's headers and cookies are kept on the 304 or 412
+    let status = response_meta.status.unwrap_or(body.status_code());
+    if (200..300).contains(&status) {
+        let precondition = evaluate_preconditions(
+            request,
+            response_meta.etag.as_deref(),
+            response_meta.last_modified,
+        );
+        if precondition != Precondition::Proceed {
+            // The body is dropped unread, so a streamed body is never produced
+            let compressible = match compression {
+                Some(config) => body.is_compressible(response_meta.clone(), config)?,
+                None => false,
+            };
+            let response = match precondition {
+                Precondition::NotModified => response_meta
+                    .apply(tiny_http::Response::empty(304).boxed())?
+                    .with_status_code(304),
+                _ => {
+                    let error = LabeledError::new("Precondition Failed").with_code("http::412");
+                    response_meta.status = None;
+                    response_meta.apply(reject(error))?
+                }
+            };
+            return Ok(response.with_compression_validators(request, compressible));
+        }
+    }
+
+    match body {
+        // Buffered bodies can be seeked, so they answer Range requests
+        Body::Buffered(response) => Ok(response_meta.apply(response)?.with_ranges(request)),
+        Body::Streamed(response) => response_meta.apply(response),
+    }
+}
+
+/// A response body before `http.response` metadata is applied
+enum Body {
+    Buffered(tiny_http::Response<Cursor<Vec<u8>>>),
+    Streamed(tiny_http::ResponseBox),
+}
+
+impl Body {
+    fn status_code(&self) -> u16 {
+        match self {
+            Body::Buffered(response) => response.status_code().0,
+            Body::Streamed(response) => response.status_code().0,
+        }
+    }
+
+    /// Whether the response would be compressed once `response_meta` is applied
+    fn is_compressible(
+        self,
+        response_meta: ResponseMeta,
+        config: &tiny_http::CompressionConfig,
+    ) -> Result<bool, LabeledError> {
+        Ok(match self {
+            Body::Buffered(response) => response_meta.apply(response)?.is_compressible(config),
+            Body::Streamed(response) => response_meta.apply(response)?.is_compressible(config),
+        })
+    }
+}
+
+/// Answer 406 with the content types the value could have been sent as
+fn not_acceptable(
+    value: &Value,
+    reject: &dyn Fn(LabeledError) -> tiny_http::ResponseBox,
+) -> tiny_http::ResponseBox {
+    let available = available_content_types(value).join(", ");
+    let error =
+        LabeledError::new(format!("Not Acceptable. Available: {available}")).with_code("http::406");
+    reject(error).with_header(
+        tiny_http::Header::from_bytes(&b"Vary"[..], &b"Accept"[..]).expect("Invalid Vary header"),
+    )
+}
+
+/// Convert the body of PipelineData to tiny_http::Response
+///
+/// Records and lists are encoded in `format`, list streams not sent as events in
+/// `stream_format`. Buffered bodies get an ETag computed from their content, unless
+/// the closure set one.
+#[allow(clippy::too_many_arguments)]
+fn body_to_response(
+    engine: &EngineInterface,
+    pipeline_data: PipelineData,
+    request: &tiny_http::Request,
+    format: Option<Format>,
+    stream_format: Option<(StreamFormat, String)>,
+    json: JsonOptions,
+    response_meta: &mut ResponseMeta,
+    span: Span,
+) -> Result<Body, LabeledError> {
+    let head = *request.method() == tiny_http::Method::Head;
+    let (stream_format, stream_content_type) = stream_format.unwrap_or_else(|| {
+        let format = json.stream_format;
+        (format, format.content_type().to_string())
+    });
+
+    let response = match pipeline_data {
+        // Empty or Nothing -> 204 No Content with empty body
+        PipelineData::Empty => tiny_http::Response::from_data(Vec::new()).with_status_code(204),
+
+        // Value -> serialize to bytes
+        PipelineData::Value(value, meta) => {
+            let (body, default_content_type) = match value {
+                Value::Nothing { .. } => {
+                    return Ok(Body::Buffered(
+                        tiny_http::Response::from_data(Vec::new()).with_status_code(204),
+                    ))
+                }
+                // Records and Lists -> the negotiated format
+                Value::Record { .. } | Value::List { .. } => {
+                    let format = format.unwrap_or(Format::Json);
+                    (
+                        format.encode(engine, value, json, span)?,
+                        format.content_type(),
+                    )
+                }
+                // Other values -> text/plain
+                _ => (value_to_bytes(value, json), "text/plain; charset=utf-8"),
+            };
+            if response_meta.etag.is_none() {
+                response_meta.etag = Some(body_etag(&body));
+            }
+            let content_type = infer_content_type(&meta, Some(default_content_type));
+            tiny_http::Response::from_data(body).with_header(content_type_header(&content_type)?)
+        }
+
+        // HEAD -> headers only; streams are dropped unread so they are never produced
+        PipelineData::ListStream(_, meta) if head => {
+            let content_type = if wants_event_stream(&meta, request) {
+                EVENT_STREAM
+            } else {
+                &stream_content_type
+            };
+            return streaming_response(Box::new(std::io::empty()), content_type)
+                .map(Body::Streamed);
+        }
+        PipelineData::ByteStream(_, meta) if head => {
+            let content_type = infer_content_type(&meta, Some("application/octet-stream"));
+            return streaming_response(Box::new(std::io::empty()), &content_type)
+                .map(Body::Streamed);
+        }
+
+        // ListStream -> Server-Sent Events if requested by the client or the closure
+        PipelineData::ListStream(stream, meta) if wants_event_stream(&meta, request) => {
+            let reader = SseReader::new(stream, json);
+            let response = streaming_response(Box::new(reader), EVENT_STREAM)?.with_header(
+                tiny_http::Header::from_bytes(&b"Cache-Control"[..], &b"no-cache"[..])
+                    .expect("Invalid Cache-Control header"),
+            );
+            return Ok(Body::Streamed(response));
+        }
+
+        // ListStream -> stream items as they arrive, as a JSON array or NDJSON
+        PipelineData::ListStream(stream, _) => {
+            let reader = ListStreamReader::new(stream, stream_format, json);
+            return streaming_response(Box::new(reader), &stream_content_type).map(Body::Streamed);
+        }
+
+        // ByteStream -> stream to response
+        PipelineData::ByteStream(stream, meta) => match stream.reader() {
+            Some(reader) => {
+                let content_type = infer_content_type(&meta, Some("application/octet-stream"));
+                return streaming_response(Box::new(reader), &content_type).map(Body::Streamed);
+            }
+            None => return Err(LabeledError::new("ByteStream has no reader")),
+        },
+    };
+
+    Ok(Body::Buffered(response))
+}
+
+/// Whether a ListStream should be sent as Server-Sent Events
+///
+/// The closure's content type wins; otherwise the client's Accept header decides.
+fn wants_event_stream(meta: &Option<PipelineMetadata>, request: &tiny_http::Request) -> bool {
+    match meta.as_ref().and_then(|m| m.content_type.as_deref()) {
+        Some(content_type) => content_type.starts_with(EVENT_STREAM),
+        None => accepts_event_stream(request),
+    }
+}
+
+/// Framing and content type of a ListStream
+///
+/// The closure's content type wins, then the client's Accept header, then the
+/// `--ndjson` flag; a negotiated framing gets `Vary: Accept`. `None` means the stream
+/// has to be collected: the content type names a format that isn't written item by
+/// item (e.g. text/csv), or the client accepts neither framing. A content type no
+/// format is known for is an error, rather than a body labelled as something it isn't.
+fn list_stream_format(
+    meta: &Option<PipelineMetadata>,
+    request: &tiny_http::Request,
+    json: JsonOptions,
+    response_meta: &mut ResponseMeta,
+) -> Result<Option<(StreamFormat, String)>, LabeledError> {
+    match meta.as_ref().and_then(|m| m.content_type.as_deref()) {
+        Some(content_type) => match StreamFormat::from_content_type(content_type) {
+            Some(format) => Ok(Some((format, content_type.to_string()))),
+            None if Format::from_content_type(content_type).is_some() => Ok(None),
+            None => Err(
+                LabeledError::new(format!("Cannot send a list stream as {content_type}"))
+                    .with_help(
+                        "use a structured content type such as application/json, \
+                         application/x-ndjson or text/csv, or convert the stream to a string",
+                    ),
+            ),
+        },
+        None => {
+            let format = negotiate_stream_format(request, json.stream_format);
+            if format.is_some() {
+                response_meta
+                    .headers
+                    .push(("Vary".to_string(), "Accept".to_string()));
+            }
+            Ok(format.map(|format| (format, format.content_type().to_string())))
+        }
+    }
+}
+
+/// Build a chunked response that sends data to the client as soon as it is read
+fn streaming_response(
+    reader: Box<dyn Read + Send>,
+    content_type: &str,
+) -> Result<tiny_http::ResponseBox, LabeledError> {
+    Ok(tiny_http::Response::new(
+        tiny_http::StatusCode(200),
+        vec![content_type_header(content_type)?],
+        reader,
+        None,
+        None,
+    )
+    .with_streaming(true))
+}
+
+/// `Read` adapter over a ListStream, serializing one item per read
+///
+/// Items are written as JSON, either inside a JSON array or one per line. An error
+/// value in the stream aborts the response, since the status line has already been
+/// sent by then.
+struct ListStreamReader {
+    iter: Box<dyn Iterator<Item = Value> + Send>,
+    format: StreamFormat,
+    json: JsonOptions,
+    /// Whether any item was written yet
+    started: bool,
+    finished: bool,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl ListStreamReader {
+    fn new(stream: ListStream, format: StreamFormat, json: JsonOptions) -> Self {
+        Self {
+            iter: Box::new(stream.into_iter()),
+            format,
+            json,
+            started: false,
+            finished: false,
+            buffer: Vec::new(),
+            position: 0,
+        }
+    }
+
+    /// Serialize the next item, or the end of the array
+    fn fill_buffer(&mut self) -> std::io::Result<()> {
+        self.buffer.clear();
+        self.position = 0;
+
+        match self.iter.next() {
+            Some(Value::Error { error, .. }) => {
+                return Err(std::io::Error::other(error.to_string()))
+            }
+            Some(value) => {
+                if self.format == StreamFormat::JsonArray {
+                    self.buffer.push(if self.started { b',' } else { b'[' });
+                }
+                serde_json::to_writer(&mut self.buffer, &value_to_json(&value, self.json))
+                    .map_err(std::io::Error::other)?;
+                if self.format == StreamFormat::Ndjson {
+                    self.buffer.push(b'\n');
+                }
+                self.started = true;
+            }
+            None => {
+                self.finished = true;
+                if self.format == StreamFormat::JsonArray {
+                    let end: &[u8] = if self.started { b"]" } else { b"[]" };
+                    self.buffer.extend_from_slice(end);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for ListStreamReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position >= self.buffer.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.fill_buffer()?;
+        }
+
+        let remaining = &self.buffer[self.position..];
+        let len = remaining.len().min(buf.len());
+        buf[..len].copy_from_slice(&remaining[..len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+/// Infer content-type from metadata or use default
+fn infer_content_type(
+    meta: &Option<nu_protocol::PipelineMetadata>,
+    default: Option<&str>,
+) -> String {
+    meta.as_ref()
+        .and_then(|m| m.content_type.as_ref())
+        .map(|s| s.to_string())
+        .or_else(|| default.map(|s| s.to_string()))
+        .unwrap_or_else(|| "text/plain; charset=utf-8".to_string())
+}
+
+/// Create Content-Type header
+fn content_type_header(content_type: &str) -> Result<tiny_http::Header, LabeledError> {
+    tiny_http::Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes())
+        .map_err(|_| LabeledError::new(format!("Invalid Content-Type header: {content_type}")))
+}
+
+/// Convert Nu Value to bytes for HTTP response body
+///
+/// Strings and binary are sent as-is, lists and records as JSON, and other values as
+/// their JSON form without quotes (e.g. a date as its RFC 3339 string).
+pub fn value_to_bytes(value: Value, json: JsonOptions) -> Vec<u8> {
+    match value {
+        Value::Nothing { .. } => Vec::new(),
+        Value::String { val, .. } => val.into_bytes(),
+        Value::Int { val, .. } => val.to_string().into_bytes(),
+        Value::Float { val, .. } => val.to_string().into_bytes(),
+        Value::Binary { val, .. } => val,
+        Value::Bool { val, .. } => val.to_string().into_bytes(),
+
+        // Lists and Records -> JSON (following http-nu pattern)
+        Value::List { .. } | Value::Record { .. } => {
+            serde_json::to_string(&value_to_json(&value, json))
+                .unwrap_or_else(|_| String::new())
+                .into_bytes()
+        }
+
+        _ => match value_to_json(&value, json) {
+            serde_json::Value::String(val) => val.into_bytes(),
+            other => other.to_string().into_bytes(),
+        },
+    }
+}
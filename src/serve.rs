@@ -1,13 +1,20 @@
 use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
 use nu_protocol::{
-    engine::Closure, IntoSpanned, LabeledError, PipelineData, Record, Signature, Span, Spanned,
-    SyntaxShape, Type, Value,
+    engine::Closure, ByteStream, ByteStreamType, IntoSpanned, LabeledError, PipelineData,
+    PipelineMetadata, Signals, Signature, Span, Spanned, SyntaxShape, Type, Value,
 };
-use std::io::Read;
 use std::path::Path;
-use std::sync::mpsc;
+use std::sync::atomic::AtomicBool;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
 use std::time::Duration;
 
+use crate::error::{error_status, error_to_value, problem_response};
+use crate::json::{duration_unit, filesize_unit, JsonOptions, StreamFormat};
+use crate::request::{decode_body, request_to_value};
+use crate::response::{pipeline_data_to_response, with_default_status};
+use crate::sse::EVENT_STREAM;
+use crate::websocket::handle_websocket;
 use crate::HttpServePlugin;
 
 pub struct HttpServe;
@@ -23,6 +30,119 @@ impl PluginCommand for HttpServe {
         "Start an HTTP server that evaluates a closure for each request"
     }
 
+    fn extra_description(&self) -> &str {
+        r#"The closure receives the request as a record and the request body as $in.
+Header names in $req.headers are lowercase; a header sent more than once is a list.
+$req.path is percent-decoded without the query string ($req.uri is the raw target).
+$req.query is decoded too: `?debug` gives true and a repeated key gives a list.
+
+With --decode-body, JSON, form (application/x-www-form-urlencoded), text/* and msgpack
+bodies arrive as Nu values instead of a byte stream; a body that fails to parse is
+answered with 400 before the closure runs. Other bodies are still streamed; use
+`http multipart` to read multipart/form-data uploads part by part.
+
+Records and lists are sent in the format the Accept header asks for: JSON (the default),
+NUON, MessagePack, CSV or TSV (tables only), YAML or an HTML table. When none of them is
+acceptable the answer is 406. The closure can force a format by setting the content type:
+    $rows | metadata set --content-type text/csv
+
+In JSON, dates are RFC 3339 strings, binary is base64, ranges are {start, end, step,
+inclusive} objects, and durations and filesizes are numbers in --duration-unit and
+--filesize-unit (nanoseconds and bytes by default, as in `to json`).
+
+Set the response status and headers through pipeline metadata:
+    "not found" | metadata set --merge {'http.response': {status: 404}}
+    null | metadata set --merge {'http.response': {status: 302, headers: {location: "/"}}}
+
+A header given as a list is sent once per item. To redirect, use `http redirect`:
+    http redirect /login --status 303
+
+When the closure fails, the answer is a 500 with an RFC 7807 application/problem+json
+body; --debug adds the error message, help and the source it points at. Use
+`http error 404 "no such page"` (or `error make` with code "http::404") to answer
+with another status and send the message to the client. An --on-error closure can
+build the response instead: it gets the error as a record (msg, status, labels with
+text and source, help, code) and the request, and its output is sent with that
+status unless it sets another one:
+    --on-error {|err, req| {error: $err.msg} | metadata set --merge {'http.response': {status: 503}}}
+The 400, 406, 412 and 413 answers the plugin makes itself are errors with that status
+too, so they go through --on-error and problem details the same way.
+
+Request cookies are in $req.cookies. To set cookies, give a record of name to value,
+or to a record with value, path, domain, max_age, expires, http_only, secure, same_site:
+    "ok" | metadata set --merge {'http.response': {cookies: {session: {value: "abc", http_only: true}}}}
+
+Buffered responses get an ETag computed from the body. The closure can set its own
+etag and last_modified (a date) in 'http.response'; If-None-Match, If-Modified-Since,
+If-Match and If-Unmodified-Since on GET and HEAD requests are then answered with 304
+or 412 automatically. Other methods are left to the closure, which runs before the
+response is known: check $req.headers.if-match before changing anything.
+
+Buffered responses (strings, binary and structured values) also answer Range requests
+(206 Partial Content, multipart/byteranges for several ranges, 416 when unsatisfiable,
+honoring If-Range). Streams, including files the closure opens, reach the plugin with no
+known length and can't be seeked, so they are always sent whole.
+
+With --compress, responses are compressed with the best coding in Accept-Encoding
+(zstd, br, gzip or deflate), including streams. Only the types in --compress-types
+(text/*, JSON, JavaScript, XML and SVG by default) at least --compress-min-size big
+are compressed. Their ETag becomes weak and they get Vary: Accept-Encoding, as do the
+304 and 412 answered in their place.
+
+HEAD requests run the closure like GET (check $req.method to tell them apart) and are
+answered with the same headers but no body. A streamed body is never read, so a
+closure that returns a stream does no work for it.
+
+Streams are sent as they are produced. A list stream is sent as Server-Sent Events
+when the client accepts text/event-stream or the closure sets that content type;
+records with event, id, data or retry fields map to the matching SSE fields.
+Otherwise it is a JSON array written item by item, or newline-delimited JSON when the
+client accepts application/x-ndjson or application/jsonl (or with --ndjson). When the
+client accepts neither, or the closure sets another format's content type (text/csv),
+the stream is collected and sent like a list, or answered with 406.
+
+With --websocket, upgrade requests run that closure instead. It receives the request
+record and incoming messages as a list stream on $in; each value it outputs is sent
+back as a frame (strings as text, binary as binary, other values as JSON text):
+    http serve :3000 {|req| "hello"} --websocket {|req| each {|msg| $"echo: ($msg)"}}
+
+On Ctrl-C the server stops accepting connections and waits up to --shutdown-timeout
+(10sec by default) for the requests in flight to finish. Their responses close
+keep-alive connections. Requests still running after the timeout are abandoned: the
+server returns without waiting for them. Like with --handler-timeout, a closure still
+reading the request body is interrupted, but any other evaluation finishes in the
+background. How many requests were drained and abandoned is reported on stderr.
+
+--max-concurrency bounds how many requests are handled at once. Further requests wait
+in a queue of at most --max-queue requests (4 times --max-concurrency by default);
+when it is full they are answered with 503 Service Unavailable and a Retry-After of
+--retry-after (1sec by default), as are the requests still queued on Ctrl-C. WebSocket
+connections and text/event-stream responses can stay open indefinitely, so they give
+up their slot once the upgrade is accepted or the stream starts, and don't count
+toward the limit. Each open connection still has its own thread reading its requests:
+bound those with --max-connections.
+
+With --handler-timeout, a closure that hasn't returned in time is answered with
+504 Gateway Timeout (through --on-error if given). A closure still reading the request
+body is interrupted, but the plugin protocol has no way to interrupt the closure's
+evaluation in the engine: it is abandoned, finishes in the background and its output
+is discarded. An abandoned evaluation no longer counts toward --max-concurrency or as
+in flight on shutdown. A response stream isn't limited once the closure has returned it.
+
+Slow or idle clients are cut off by connection timeouts, all unlimited by default:
+--header-timeout for the request line and headers (answered with 408),
+--body-timeout between reads of the request body (answered with 408 when the closure
+fails reading it), --keep-alive-timeout between requests on a connection, and
+--write-timeout for the client to take the response.
+--max-requests-per-connection closes keep-alive connections after that many requests,
+and connections beyond --max-connections are closed right away.
+
+Request sizes can be limited too: a URL longer than --max-url-length is answered with
+414, headers beyond --max-header-size or --max-header-count with 431, and a body larger
+than --max-body-size with 413 (also when a chunked body turns out too large while the
+closure reads it)."#
+    }
+
     fn signature(&self) -> Signature {
         Signature::build(PluginCommand::name(self))
             .required(
@@ -35,6 +155,155 @@ impl PluginCommand for HttpServe {
                 SyntaxShape::Closure(Some(vec![SyntaxShape::Record(vec![])])),
                 "The closure to evaluate for each HTTP request",
             )
+            .named(
+                "websocket",
+                SyntaxShape::Closure(Some(vec![SyntaxShape::Record(vec![])])),
+                "The closure to evaluate for each WebSocket connection",
+                None,
+            )
+            .switch(
+                "decode-body",
+                "Decode JSON, form, text and msgpack request bodies before passing them as $in",
+                None,
+            )
+            .switch(
+                "compress",
+                "Compress responses with gzip, deflate, brotli or zstd as the client accepts",
+                None,
+            )
+            .named(
+                "compress-min-size",
+                SyntaxShape::Filesize,
+                "Smallest response body to compress (default 1kB)",
+                None,
+            )
+            .named(
+                "compress-types",
+                SyntaxShape::List(Box::new(SyntaxShape::String)),
+                "Content types to compress: 'text/' matches a whole type, '+json' a suffix",
+                None,
+            )
+            .named(
+                "on-error",
+                SyntaxShape::Closure(Some(vec![
+                    SyntaxShape::Record(vec![]),
+                    SyntaxShape::Record(vec![]),
+                ])),
+                "Closure building the response for a failed request, given the error and request",
+                None,
+            )
+            .switch(
+                "debug",
+                "Include error messages and source in error responses",
+                None,
+            )
+            .switch(
+                "ndjson",
+                "Stream lists as newline-delimited JSON rather than a JSON array by default",
+                None,
+            )
+            .named(
+                "duration-unit",
+                SyntaxShape::String,
+                "Unit of durations in JSON: ns (default), us, ms, sec, min, hr, day or wk",
+                None,
+            )
+            .named(
+                "filesize-unit",
+                SyntaxShape::String,
+                "Unit of filesizes in JSON: b (default), kb, kib, mb, mib, gb, gib, ...",
+                None,
+            )
+            .named(
+                "shutdown-timeout",
+                SyntaxShape::Duration,
+                "How long to wait for requests in flight on Ctrl-C (default 10sec)",
+                None,
+            )
+            .named(
+                "max-concurrency",
+                SyntaxShape::Int,
+                "Most requests handled at once, the others waiting in a queue",
+                None,
+            )
+            .named(
+                "max-queue",
+                SyntaxShape::Int,
+                "Most requests waiting for a handler before answering 503, with --max-concurrency (default 4 times it)",
+                None,
+            )
+            .named(
+                "retry-after",
+                SyntaxShape::Duration,
+                "Retry-After sent with 503 when the queue is full (default 1sec)",
+                None,
+            )
+            .named(
+                "handler-timeout",
+                SyntaxShape::Duration,
+                "How long the closure may take before answering 504",
+                None,
+            )
+            .named(
+                "header-timeout",
+                SyntaxShape::Duration,
+                "How long a client may take to send the request line and headers",
+                None,
+            )
+            .named(
+                "body-timeout",
+                SyntaxShape::Duration,
+                "Longest wait for more of the request body",
+                None,
+            )
+            .named(
+                "keep-alive-timeout",
+                SyntaxShape::Duration,
+                "How long an idle keep-alive connection stays open",
+                None,
+            )
+            .named(
+                "write-timeout",
+                SyntaxShape::Duration,
+                "Longest wait for the client to take more of the response",
+                None,
+            )
+            .named(
+                "max-requests-per-connection",
+                SyntaxShape::Int,
+                "Requests after which a keep-alive connection is closed",
+                None,
+            )
+            .named(
+                "max-connections",
+                SyntaxShape::Int,
+                "Most connections open at once, others being closed right away",
+                None,
+            )
+            .named(
+                "max-url-length",
+                SyntaxShape::Int,
+                "Longest request URL, in bytes, before answering 414",
+                None,
+            )
+            .named(
+                "max-header-size",
+                SyntaxShape::Filesize,
+                "Largest request line and headers before answering 431",
+                None,
+            )
+            .named(
+                "max-header-count",
+                SyntaxShape::Int,
+                "Most request headers before answering 431",
+                None,
+            )
+            .named(
+                "max-body-size",
+                SyntaxShape::Filesize,
+                "Largest request body before answering 413",
+                None,
+            )
             .input_output_type(Type::Any, Type::Any)
     }
 
@@ -50,6 +319,52 @@ impl PluginCommand for HttpServe {
         // Parse arguments
         let socket_path = call.req::<Value>(0)?.into_string()?;
         let closure = call.req::<Value>(1)?.into_closure()?.into_spanned(span);
+        let max_concurrency = count_flag(call, "max-concurrency")?;
+        if max_concurrency.is_none() {
+            if let Some(max_queue) = call.get_flag::<Spanned<i64>>("max-queue")? {
+                return Err(
+                    LabeledError::new("--max-queue needs --max-concurrency").with_label(
+                        "requests only wait in a queue when --max-concurrency is set",
+                        max_queue.span,
+                    ),
+                );
+            }
+        }
+        let options = ServeOptions {
+            websocket: call
+                .get_flag::<Value>("websocket")?
+                .map(|value| value.into_closure().map(|c| c.into_spanned(span)))
+                .transpose()?,
+            decode_body: call.has_flag("decode-body")?,
+            compression: compression_config(call)?,
+            json: json_options(call)?,
+            on_error: call
+                .get_flag::<Value>("on-error")?
+                .map(|value| value.into_closure().map(|c| c.into_spanned(span)))
+                .transpose()?,
+            debug: call.has_flag("debug")?,
+            shutdown_timeout: duration_flag(call, "shutdown-timeout")?
+                .unwrap_or(Duration::from_secs(10)),
+            max_concurrency,
+            handler_timeout: duration_flag(call, "handler-timeout")?,
+            limits: tiny_http::ServerLimits {
+                max_pending_requests: count_flag(call, "max-queue")?
+                    .or(max_concurrency.map(|max_concurrency| max_concurrency * 4)),
+                retry_after: Some(
+                    duration_flag(call, "retry-after")?.unwrap_or(Duration::from_secs(1)),
+                ),
+                header_read_timeout: duration_flag(call, "header-timeout")?,
+                body_read_timeout: duration_flag(call, "body-timeout")?,
+                keep_alive_timeout: duration_flag(call, "keep-alive-timeout")?,
+                write_timeout: duration_flag(call, "write-timeout")?,
+                max_requests_per_connection: count_flag(call, "max-requests-per-connection")?,
+                max_connections: count_flag(call, "max-connections")?,
+                max_url_length: count_flag(call, "max-url-length")?,
+                max_header_bytes: count_flag(call, "max-header-size")?,
+                max_header_count: count_flag(call, "max-header-count")?,
+                max_body_size: count_flag(call, "max-body-size")?,
+            },
+        };
 
         // Register signal handler for Ctrl-C
         let (shutdown_tx, shutdown_rx) = mpsc::channel();
@@ -58,7 +373,15 @@ impl PluginCommand for HttpServe {
         }))?;
 
         // Start the HTTP server
-        serve(engine, span, closure, socket_path, shutdown_rx, _guard)?;
+        serve(
+            engine,
+            span,
+            closure,
+            options,
+            socket_path,
+            shutdown_rx,
+            _guard,
+        )?;
 
         Ok(PipelineData::Value(
             Value::string("Server stopped", span),
@@ -67,11 +390,110 @@ impl PluginCommand for HttpServe {
     }
 }
 
+/// Options for `http serve`, shared by every request
+#[derive(Clone)]
+struct ServeOptions {
+    /// Closure handling WebSocket upgrade requests
+    websocket: Option<Spanned<Closure>>,
+    /// Decode the request body by Content-Type before running the closure
+    decode_body: bool,
+    /// Compress responses according to Accept-Encoding
+    compression: Option<tiny_http::CompressionConfig>,
+    /// Units of durations and filesizes in JSON output
+    json: JsonOptions,
+    /// Closure building the response for a failed request
+    on_error: Option<Spanned<Closure>>,
+    /// Show error details in problem responses
+    debug: bool,
+    /// How long to wait for requests in flight on shutdown
+    shutdown_timeout: Duration,
+    /// Most requests handled at once
+    max_concurrency: Option<usize>,
+    /// How long the closure may take to return a response
+    handler_timeout: Option<Duration>,
+    /// Limits of the tiny-http server
+    limits: tiny_http::ServerLimits,
+}
+
+/// Read a duration flag, which must not be negative
+fn duration_flag(call: &EvaluatedCall, name: &str) -> Result<Option<Duration>, LabeledError> {
+    call.get_flag::<Spanned<i64>>(name)?
+        .map(|duration| {
+            u64::try_from(duration.item)
+                .map(Duration::from_nanos)
+                .map_err(|_| {
+                    LabeledError::new(format!("Invalid --{name}"))
+                        .with_label("must not be negative", duration.span)
+                })
+        })
+        .transpose()
+}
+
+/// Read a flag counting something, which must be at least 1
+fn count_flag(call: &EvaluatedCall, name: &str) -> Result<Option<usize>, LabeledError> {
+    call.get_flag::<Spanned<i64>>(name)?
+        .map(|count| {
+            usize::try_from(count.item)
+                .ok()
+                .filter(|count| *count > 0)
+                .ok_or_else(|| {
+                    LabeledError::new(format!("Invalid --{name}"))
+                        .with_label("must be at least 1", count.span)
+                })
+        })
+        .transpose()
+}
+
+/// Build the compression settings from the `--compress*` flags
+fn compression_config(
+    call: &EvaluatedCall,
+) -> Result<Option<tiny_http::CompressionConfig>, LabeledError> {
+    if !call.has_flag("compress")? {
+        return Ok(None);
+    }
+
+    let mut config = tiny_http::CompressionConfig::default();
+    if let Some(min_size) = call.get_flag::<i64>("compress-min-size")? {
+        config.min_size = usize::try_from(min_size).map_err(|_| {
+            LabeledError::new("Invalid --compress-min-size")
+                .with_label("must not be negative", call.head)
+        })?;
+    }
+    if let Some(content_types) = call.get_flag::<Vec<String>>("compress-types")? {
+        config.content_types = content_types;
+    }
+    Ok(Some(config))
+}
+
+/// Build the JSON settings from `--ndjson`, `--duration-unit` and `--filesize-unit`
+fn json_options(call: &EvaluatedCall) -> Result<JsonOptions, LabeledError> {
+    let mut options = JsonOptions::default();
+    if call.has_flag("ndjson")? {
+        options.stream_format = StreamFormat::Ndjson;
+    }
+    if let Some(unit) = call.get_flag::<Spanned<String>>("duration-unit")? {
+        options.duration_unit = duration_unit(&unit.item).ok_or_else(|| {
+            LabeledError::new("Invalid --duration-unit").with_label(
+                "expected one of: ns, us, ms, sec, min, hr, day, wk",
+                unit.span,
+            )
+        })?;
+    }
+    if let Some(unit) = call.get_flag::<Spanned<String>>("filesize-unit")? {
+        options.filesize_unit = filesize_unit(&unit.item).ok_or_else(|| {
+            LabeledError::new("Invalid --filesize-unit")
+                .with_label("expected a filesize unit such as b, kb or kib", unit.span)
+        })?;
+    }
+    Ok(options)
+}
+
 /// Start HTTP server and handle requests
 fn serve(
     engine: &EngineInterface,
     span: Span,
     closure: Spanned<Closure>,
+    options: ServeOptions,
     socket_path: String,
     shutdown_rx: mpsc::Receiver<()>,
     _guard: nu_protocol::HandlerGuard,
@@ -101,20 +523,23 @@ fn serve(
         socket_path.clone()
     };
 
-    let server = if is_tcp {
+    let (addr, description) = if is_tcp {
         // TCP socket
-        tiny_http::Server::http(&socket_path).map_err(|e| {
+        let addr = tiny_http::ConfigListenAddr::from_socket_addrs(&socket_path).map_err(|e| {
             LabeledError::new(format!("Failed to bind to TCP {}: {}", socket_path, e))
-        })?
+        })?;
+        (addr, format!("TCP {}", socket_path))
     } else {
         // Unix socket
-        tiny_http::Server::http_unix(Path::new(&resolved_socket_path)).map_err(|e| {
-            LabeledError::new(format!(
-                "Failed to bind to Unix socket {}: {}",
-                resolved_socket_path, e
-            ))
-        })?
+        let addr = tiny_http::ConfigListenAddr::unix_from_path(&resolved_socket_path);
+        (addr, format!("Unix socket {}", resolved_socket_path))
     };
+    let server = tiny_http::Server::new(tiny_http::ServerConfig {
+        addr,
+        ssl: None,
+        limits: options.limits.clone(),
+    })
+    .map_err(|e| LabeledError::new(format!("Failed to bind to {}: {}", description, e)))?;
 
     if is_tcp {
         eprintln!("Listening on http://{}", socket_path);
@@ -122,6 +547,33 @@ fn serve(
         eprintln!("Listening on {} (Unix socket)", resolved_socket_path);
     }
 
+    // Handler threads of the requests in flight, with the signals interrupting them
+    let mut handlers: Vec<(JoinHandle<()>, Signals)> = Vec::new();
+    let in_flight = InFlight::default();
+    let spawn_handler = |request: tiny_http::Request, slot: Option<Slot>| {
+        let engine = engine.clone();
+        let closure = closure.clone();
+        let options = options.clone();
+        let signals = Signals::new(Arc::new(AtomicBool::new(false)));
+        let handler_signals = signals.clone();
+        let handling = in_flight.enter();
+
+        let handler = std::thread::spawn(move || {
+            let _handling = handling;
+            handle_request(
+                engine,
+                span,
+                closure,
+                options,
+                request,
+                slot,
+                handler_signals,
+            );
+        });
+        (handler, signals)
+    };
+    let slots = options.max_concurrency.map(Slots::new);
+
     // Accept connections in a loop
     loop {
         // Check for shutdown signal (non-blocking)
@@ -130,16 +582,21 @@ fn serve(
             break;
         }
 
+        // At --max-concurrency, leave requests queued in the server until a handler is done
+        let slot = match &slots {
+            Some(slots) => match slots.acquire(Duration::from_millis(100)) {
+                Some(slot) => Some(slot),
+                None => continue,
+            },
+            None => None,
+        };
+
         // Blocking receive with timeout - responsive to Ctrl-C, zero request latency
         match server.recv_timeout(Duration::from_millis(100)) {
             Ok(Some(request)) => {
                 // Spawn a thread to handle this request
-                let engine = engine.clone();
-                let closure = closure.clone();
-
-                std::thread::spawn(move || {
-                    handle_request(engine, span, closure, request);
-                });
+                handlers.retain(|(handler, _)| !handler.is_finished());
+                handlers.push(spawn_handler(request, slot));
             }
             Ok(None) => {
                 // Timeout - loop continues, will check shutdown signal
@@ -151,218 +608,453 @@ fn serve(
         }
     }
 
+    // Stop accepting connections, but still handle the requests already received; at
+    // --max-concurrency, those still waiting for a slot are turned away like a full queue
+    server.drain();
+    while let Ok(Some(request)) = server.try_recv() {
+        if slots.is_some() {
+            let _ = request.respond(busy_response(&options.limits));
+        } else {
+            handlers.push(spawn_handler(request, None));
+        }
+    }
+
+    drain_handlers(handlers, &in_flight, options.shutdown_timeout);
     Ok(())
 }
 
+/// The 503 sent to requests turned away for lack of a handler, like tiny-http's own for
+/// a full queue
+fn busy_response(limits: &tiny_http::ServerLimits) -> tiny_http::ResponseBox {
+    let mut response = tiny_http::Response::from_string("Server is busy").with_status_code(503);
+    if let Some(retry_after) = limits.retry_after {
+        let retry_after = retry_after.as_secs().to_string();
+        response.add_header(
+            tiny_http::Header::from_bytes(&b"Retry-After"[..], retry_after.as_bytes()).unwrap(),
+        );
+    }
+    response.boxed()
+}
+
+/// Counting semaphore of the requests that may be handled at once
+#[derive(Clone)]
+struct Slots(Arc<(Mutex<usize>, Condvar)>);
+
+impl Slots {
+    fn new(count: usize) -> Self {
+        Slots(Arc::new((Mutex::new(count), Condvar::new())))
+    }
+
+    /// Take a free slot, waiting up to `timeout` for a handler to release one
+    fn acquire(&self, timeout: Duration) -> Option<Slot> {
+        let (free, released) = &*self.0;
+        let free = free.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut free, _) = released
+            .wait_timeout_while(free, timeout, |free| *free == 0)
+            .unwrap_or_else(|e| e.into_inner());
+        if *free == 0 {
+            return None;
+        }
+        *free -= 1;
+        Some(Slot(self.clone()))
+    }
+}
+
+/// A taken slot, released when dropped
+struct Slot(Slots);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let (free, released) = &*(self.0).0;
+        *free.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        released.notify_one();
+    }
+}
+
+/// Count of the handler threads running, which shutdown waits on
+#[derive(Clone, Default)]
+struct InFlight(Arc<(Mutex<usize>, Condvar)>);
+
+impl InFlight {
+    /// Count a handler until the returned guard is dropped
+    fn enter(&self) -> Handling {
+        let (count, _) = &*self.0;
+        *count.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        Handling(self.clone())
+    }
+
+    fn count(&self) -> usize {
+        let (count, _) = &*self.0;
+        *count.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Wait up to `timeout` for every handler to finish, returning how many are left
+    fn wait(&self, timeout: Duration) -> usize {
+        let (count, finished) = &*self.0;
+        let count = count.lock().unwrap_or_else(|e| e.into_inner());
+        let (count, _) = finished
+            .wait_timeout_while(count, timeout, |count| *count > 0)
+            .unwrap_or_else(|e| e.into_inner());
+        *count
+    }
+}
+
+/// A running handler, no longer counted when dropped
+struct Handling(InFlight);
+
+impl Drop for Handling {
+    fn drop(&mut self) {
+        let (count, finished) = &*(self.0).0;
+        *count.lock().unwrap_or_else(|e| e.into_inner()) -= 1;
+        finished.notify_all();
+    }
+}
+
+/// Wait up to `timeout` for the handlers of requests in flight, then report how many
+/// finished and how many are abandoned
+///
+/// The handlers still running are interrupted, but not waited for: their threads keep
+/// running until the closure returns. Returns how many were abandoned.
+fn drain_handlers(
+    handlers: Vec<(JoinHandle<()>, Signals)>,
+    in_flight: &InFlight,
+    timeout: Duration,
+) -> usize {
+    let running = in_flight.count();
+    if running == 0 {
+        return 0;
+    }
+
+    eprintln!("Waiting for {running} request(s) in flight...");
+    let abandoned = in_flight.wait(timeout);
+    for (handler, signals) in &handlers {
+        if !handler.is_finished() {
+            signals.trigger();
+        }
+    }
+    eprintln!(
+        "Drained {} request(s), abandoned {abandoned}",
+        running - abandoned
+    );
+    abandoned
+}
+
 /// Handle a single HTTP request
+///
+/// `slot` is held until the response is sent, except for WebSocket upgrades and event
+/// streams, which are long-lived and release it before they start. Triggering `signals`
+/// interrupts the closure's reads of the request body.
 fn handle_request(
     engine: EngineInterface,
     span: Span,
     closure: Spanned<Closure>,
-    request: tiny_http::Request,
+    options: ServeOptions,
+    mut request: tiny_http::Request,
+    slot: Option<Slot>,
+    signals: Signals,
 ) {
     // Convert HTTP request to Nu Value
     let request_value = request_to_value(&request, span);
 
+    // Hand WebSocket upgrades to their own closure
+    if let Some(websocket) = options.websocket {
+        if request.is_websocket_upgrade() {
+            drop(slot);
+            handle_websocket(
+                engine,
+                span,
+                websocket,
+                request,
+                request_value,
+                options.json,
+            );
+            return;
+        }
+    }
+
+    // Stream the request body into the closure as $in, interruptible through `signals`
+    let input = request_body_to_pipeline_data(&signals, &mut request, span);
+
+    // Decode known body types up front so that a malformed body is a 400
+    let input = if options.decode_body {
+        match decode_body(input, span) {
+            Ok(input) => input,
+            Err(error_msg) => {
+                let status = body_error(&request).map_or(400, |(status, _)| status);
+                let error = LabeledError::new(error_msg).with_code(format!("http::{status}"));
+                let response =
+                    error_response(&engine, span, &options, &request, request_value, error);
+                if let Err(e) = request.respond(response) {
+                    eprintln!("Error sending error response: {}", e);
+                }
+                return;
+            }
+        }
+    } else {
+        input
+    };
+
     // Evaluate closure with request value (concurrent evaluation with cloned engine)
-    let result = engine.eval_closure_cloned_with_stream(
+    let result = eval_handler(
+        &engine,
         &closure,
-        vec![request_value],
-        PipelineData::Empty,
-        true,  // redirect_stdout
-        false, // redirect_stderr
+        request_value.clone(),
+        input,
+        options.handler_timeout,
+        &signals,
     );
 
     // Handle the result and send HTTP response
-    match result {
-        Ok(pipeline_data) => {
-            let response = pipeline_data_to_response(pipeline_data, span);
-            if let Err(e) = request.respond(response) {
-                eprintln!("Error sending response: {}", e);
-            }
-        }
+    let reject = |error| {
+        error_response(
+            &engine,
+            span,
+            &options,
+            &request,
+            request_value.clone(),
+            error,
+        )
+    };
+    let response = match result {
+        Ok(pipeline_data) => pipeline_data_to_response(
+            &engine,
+            pipeline_data,
+            &request,
+            options.json,
+            options.compression.as_ref(),
+            span,
+            &reject,
+        )
+        .inspect_err(|err| eprintln!("Error building response: {}", err)),
         Err(err) => {
-            // Send error response
             eprintln!("Error evaluating closure: {}", err);
-            let error_msg = format!("Error: {}", err);
-            let response = tiny_http::Response::from_string(error_msg).with_status_code(500);
-            if let Err(e) = request.respond(response) {
-                eprintln!("Error sending error response: {}", e);
+            match body_error(&request) {
+                Some((status, msg)) => {
+                    Err(LabeledError::new(msg).with_code(format!("http::{status}")))
+                }
+                None => Err(err),
             }
         }
+    };
+    let response = match response {
+        Ok(response) => response,
+        Err(error) => reject(error),
+    };
+
+    let response = match &options.compression {
+        Some(config) => response.with_compression(&request, config),
+        None => response,
+    };
+    if is_event_stream(&response) {
+        drop(slot);
+    }
+    if let Err(e) = request.respond(response) {
+        eprintln!("Error sending response: {}", e);
     }
 }
 
-/// Convert tiny_http::Request to Nu Value (Record)
-fn request_to_value(request: &tiny_http::Request, span: Span) -> Value {
-    let mut record = Record::new();
+/// The status and message for a request whose body couldn't be read: 413 past
+/// --max-body-size (chunked bodies), 408 past --body-timeout
+fn body_error(request: &tiny_http::Request) -> Option<(u16, &'static str)> {
+    if request.body_too_large() {
+        Some((413, "Request body is too large"))
+    } else if request.body_timed_out() {
+        Some((408, "Request body timed out"))
+    } else {
+        None
+    }
+}
 
-    // Method
-    record.push("method", Value::string(request.method().to_string(), span));
+/// Whether the response is a `text/event-stream`, sent for as long as the stream lasts
+fn is_event_stream(response: &tiny_http::ResponseBox) -> bool {
+    response.headers().iter().any(|header| {
+        header.field.equiv("Content-Type") && header.value.as_str().starts_with(EVENT_STREAM)
+    })
+}
 
-    // Path/URL
-    record.push("path", Value::string(request.url(), span));
+/// Evaluate the request closure, giving up after `timeout`
+///
+/// On timeout, `signals` is triggered to interrupt a closure reading the request body,
+/// and the error asks for a 504. The evaluation can't be interrupted in the engine, so
+/// it is left to finish on its own thread, which discards its output.
+fn eval_handler(
+    engine: &EngineInterface,
+    closure: &Spanned<Closure>,
+    request_value: Value,
+    input: PipelineData,
+    timeout: Option<Duration>,
+    signals: &Signals,
+) -> Result<PipelineData, LabeledError> {
+    let eval = move |engine: &EngineInterface, closure: &Spanned<Closure>| {
+        engine
+            .eval_closure_cloned_with_stream(
+                closure,
+                vec![request_value],
+                input,
+                true,  // redirect_stdout
+                false, // redirect_stderr
+            )
+            .map_err(|err| LabeledError::from_diagnostic(&err))
+    };
+    let Some(timeout) = timeout else {
+        return eval(engine, closure);
+    };
 
-    // Headers
-    let mut headers_record = Record::new();
-    for header in request.headers() {
-        headers_record.push(
-            header.field.to_string(),
-            Value::string(header.value.to_string(), span),
-        );
-    }
-    record.push("headers", Value::record(headers_record, span));
-
-    // Query parameters (parse from URL)
-    let mut query_record = Record::new();
-    if let Some(query_start) = request.url().find('?') {
-        let query_string = &request.url()[query_start + 1..];
-        for param in query_string.split('&') {
-            if let Some(eq_pos) = param.find('=') {
-                let key = &param[..eq_pos];
-                let value = &param[eq_pos + 1..];
-                query_record.push(key, Value::string(value, span));
-            }
+    let (result_tx, result_rx) = mpsc::channel();
+    let engine = engine.clone();
+    let closure = closure.clone();
+    std::thread::spawn(move || {
+        let _ = result_tx.send(eval(&engine, &closure));
+    });
+
+    match result_rx.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            signals.trigger();
+            Err(LabeledError::new("Request handler timed out")
+                .with_code("http::504")
+                .with_help("raise --handler-timeout if the closure needs more time"))
+        }
+        Err(mpsc::RecvTimeoutError::Disconnected) => {
+            Err(LabeledError::new("Request handler stopped unexpectedly"))
         }
     }
-    record.push("query", Value::record(query_record, span));
+}
+
+/// Build the response for a request whose handler failed
+///
+/// The status is the one the error asks for (see `http error`), 500 otherwise. The
+/// `--on-error` closure gets the error as a record and the request, and its output
+/// is sent with that status unless it sets another one. Without it, or if it fails
+/// too, the answer is an RFC 7807 problem details body.
+fn error_response(
+    engine: &EngineInterface,
+    span: Span,
+    options: &ServeOptions,
+    request: &tiny_http::Request,
+    request_value: Value,
+    error: LabeledError,
+) -> tiny_http::ResponseBox {
+    let status = error_status(&error).unwrap_or(500);
 
-    // Remote address (None for Unix sockets)
-    if let Some(addr) = request.remote_addr() {
-        record.push("remote_addr", Value::string(addr.to_string(), span));
+    if let Some(on_error) = &options.on_error {
+        let error_value = error_to_value(engine, &error, status, span);
+        let response = engine
+            .eval_closure_cloned_with_stream(
+                on_error,
+                vec![error_value, request_value],
+                PipelineData::Empty,
+                true,  // redirect_stdout
+                false, // redirect_stderr
+            )
+            .map_err(|err| LabeledError::from_diagnostic(&err))
+            .and_then(|pipeline_data| {
+                let pipeline_data = with_default_status(pipeline_data, status, span);
+                let reject = |error: LabeledError| {
+                    let status = error_status(&error).unwrap_or(500);
+                    problem_response(engine, &error, status, options.debug)
+                };
+                pipeline_data_to_response(
+                    engine,
+                    pipeline_data,
+                    request,
+                    options.json,
+                    options.compression.as_ref(),
+                    span,
+                    &reject,
+                )
+            });
+        match response {
+            Ok(response) => return response,
+            Err(err) => eprintln!("Error in --on-error closure: {}", err),
+        }
     }
 
-    Value::record(record, span)
+    problem_response(engine, &error, status, options.debug)
 }
 
-/// Convert PipelineData to tiny_http::Response
-fn pipeline_data_to_response(
-    pipeline_data: PipelineData,
-    _span: Span,
-) -> tiny_http::Response<std::io::Cursor<Vec<u8>>> {
-    match pipeline_data {
-        // Empty or Nothing -> 204 No Content with empty body
-        PipelineData::Empty => tiny_http::Response::from_data(Vec::new()).with_status_code(204),
-
-        // Value -> serialize to bytes
-        PipelineData::Value(value, meta) => {
-            match value {
-                Value::Nothing { .. } => {
-                    tiny_http::Response::from_data(Vec::new()).with_status_code(204)
-                }
-                Value::Record { .. } => {
-                    // Records -> JSON with application/json content-type
-                    let body = value_to_bytes(value);
-                    let content_type = infer_content_type(&meta, Some("application/json"));
-                    tiny_http::Response::from_data(body)
-                        .with_header(content_type_header(&content_type))
-                }
-                Value::List { .. } => {
-                    // Lists -> JSON with application/json content-type
-                    let body = value_to_bytes(value);
-                    let content_type = infer_content_type(&meta, Some("application/json"));
-                    tiny_http::Response::from_data(body)
-                        .with_header(content_type_header(&content_type))
-                }
-                _ => {
-                    // Other values -> text/plain
-                    let body = value_to_bytes(value);
-                    let content_type = infer_content_type(&meta, Some("text/plain; charset=utf-8"));
-                    tiny_http::Response::from_data(body)
-                        .with_header(content_type_header(&content_type))
-                }
-            }
-        }
+/// Convert the request body to a ByteStream without buffering it
+///
+/// Requests without a body (no Content-Length or Transfer-Encoding, or a zero
+/// Content-Length) produce `PipelineData::Empty`. If the client sent
+/// `Expect: 100-continue`, the `100 Continue` is only sent once the stream is read.
+fn request_body_to_pipeline_data(
+    signals: &Signals,
+    request: &mut tiny_http::Request,
+    span: Span,
+) -> PipelineData {
+    let has_body = match request.body_length() {
+        Some(length) => length > 0,
+        None => request
+            .headers()
+            .iter()
+            .any(|h| h.field.equiv("Transfer-Encoding")),
+    };
+    if !has_body {
+        return PipelineData::Empty;
+    }
 
-        // ListStream -> collect and serialize to JSON array
-        PipelineData::ListStream(stream, meta) => {
-            let mut body = Vec::new();
-            for value in stream.into_iter() {
-                body.extend(value_to_bytes(value));
-                body.push(b'\n'); // Separate items with newlines
-            }
-            let content_type = infer_content_type(&meta, Some("application/json"));
-            tiny_http::Response::from_data(body).with_header(content_type_header(&content_type))
-        }
+    let content_type = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Content-Type"))
+        .map(|h| h.value.to_string());
 
-        // ByteStream -> stream to response
-        PipelineData::ByteStream(stream, meta) => match stream.reader() {
-            Some(mut reader) => {
-                let mut body = Vec::new();
-                if let Err(e) = reader.read_to_end(&mut body) {
-                    eprintln!("Error reading ByteStream: {}", e);
-                    return tiny_http::Response::from_string(format!("Error: {}", e))
-                        .with_status_code(500);
-                }
-                let content_type = infer_content_type(&meta, Some("application/octet-stream"));
-                tiny_http::Response::from_data(body).with_header(content_type_header(&content_type))
-            }
-            None => {
-                eprintln!("ByteStream has no reader");
-                tiny_http::Response::from_string("Error: ByteStream has no reader")
-                    .with_status_code(500)
-            }
-        },
-    }
-}
+    let reader = request.take_reader();
+    let stream = ByteStream::read(reader, span, signals.clone(), ByteStreamType::Unknown);
+    let meta = PipelineMetadata {
+        content_type,
+        ..Default::default()
+    };
 
-/// Infer content-type from metadata or use default
-fn infer_content_type(
-    meta: &Option<nu_protocol::PipelineMetadata>,
-    default: Option<&str>,
-) -> String {
-    meta.as_ref()
-        .and_then(|m| m.content_type.as_ref())
-        .map(|s| s.to_string())
-        .or_else(|| default.map(|s| s.to_string()))
-        .unwrap_or_else(|| "text/plain; charset=utf-8".to_string())
+    PipelineData::ByteStream(stream, Some(meta))
 }
 
-/// Create Content-Type header
-fn content_type_header(content_type: &str) -> tiny_http::Header {
-    tiny_http::Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes())
-        .expect("Invalid Content-Type header")
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
 
-/// Convert Nu Value to bytes for HTTP response body
-fn value_to_bytes(value: Value) -> Vec<u8> {
-    match value {
-        Value::Nothing { .. } => Vec::new(),
-        Value::String { val, .. } => val.into_bytes(),
-        Value::Int { val, .. } => val.to_string().into_bytes(),
-        Value::Float { val, .. } => val.to_string().into_bytes(),
-        Value::Binary { val, .. } => val,
-        Value::Bool { val, .. } => val.to_string().into_bytes(),
+    /// A handler thread counted in `in_flight` that takes `duration`, whatever its signals
+    fn spawn_handler(in_flight: &InFlight, duration: Duration) -> (JoinHandle<()>, Signals) {
+        let handling = in_flight.enter();
+        let handler = std::thread::spawn(move || {
+            let _handling = handling;
+            std::thread::sleep(duration);
+        });
+        (handler, Signals::new(Arc::new(AtomicBool::new(false))))
+    }
 
-        // Lists and Records -> JSON (following http-nu pattern)
-        Value::List { .. } | Value::Record { .. } => serde_json::to_string(&value_to_json(&value))
-            .unwrap_or_else(|_| String::new())
-            .into_bytes(),
+    #[test]
+    fn drain_waits_for_handlers() {
+        let in_flight = InFlight::default();
+        let handlers = vec![
+            spawn_handler(&in_flight, Duration::from_millis(50)),
+            spawn_handler(&in_flight, Duration::from_millis(100)),
+        ];
 
-        _ => format!("{:?}", value).into_bytes(),
+        let start = Instant::now();
+        assert_eq!(
+            drain_handlers(handlers, &in_flight, Duration::from_secs(10)),
+            0
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(in_flight.count(), 0);
     }
-}
 
-/// Convert Nu Value to serde_json::Value (following http-nu pattern)
-fn value_to_json(value: &Value) -> serde_json::Value {
-    match value {
-        Value::Nothing { .. } => serde_json::Value::Null,
-        Value::Bool { val, .. } => serde_json::Value::Bool(*val),
-        Value::Int { val, .. } => serde_json::Value::Number((*val).into()),
-        Value::Float { val, .. } => serde_json::Number::from_f64(*val)
-            .map(serde_json::Value::Number)
-            .unwrap_or(serde_json::Value::Null),
-        Value::String { val, .. } => serde_json::Value::String(val.clone()),
-        Value::List { vals, .. } => {
-            serde_json::Value::Array(vals.iter().map(value_to_json).collect())
-        }
-        Value::Record { val, .. } => {
-            let mut map = serde_json::Map::new();
-            for (k, v) in val.iter() {
-                map.insert(k.clone(), value_to_json(v));
-            }
-            serde_json::Value::Object(map)
-        }
-        _ => serde_json::Value::String(format!("{:?}", value)),
+    #[test]
+    fn drain_abandons_handlers_after_timeout() {
+        let in_flight = InFlight::default();
+        let slow = spawn_handler(&in_flight, Duration::from_secs(10));
+        let slow_signals = slow.1.clone();
+        let handlers = vec![spawn_handler(&in_flight, Duration::from_millis(50)), slow];
+
+        let start = Instant::now();
+        assert_eq!(
+            drain_handlers(handlers, &in_flight, Duration::from_millis(500)),
+            1
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(slow_signals.interrupted());
     }
 }
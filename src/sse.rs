@@ -0,0 +1,158 @@
+use nu_protocol::{ListStream, Record, Value};
+use std::io::Read;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use crate::json::{value_to_json, JsonOptions};
+
+pub const EVENT_STREAM: &str = "text/event-stream";
+
+/// How long the stream may stay idle before a keep-alive comment is sent
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Whether the client asked for Server-Sent Events
+pub fn accepts_event_stream(request: &tiny_http::Request) -> bool {
+    request
+        .headers()
+        .iter()
+        .filter(|h| h.field.equiv("Accept"))
+        .flat_map(|h| h.value.as_str().split(','))
+        .any(|media| {
+            media
+                .split(';')
+                .next()
+                .is_some_and(|t| t.trim().eq_ignore_ascii_case(EVENT_STREAM))
+        })
+}
+
+/// `Read` adapter turning a ListStream into a `text/event-stream` body
+///
+/// Items are pulled on a separate thread so that a keep-alive comment can be
+/// sent while the stream is idle.
+pub struct SseReader {
+    receiver: Receiver<Value>,
+    json: JsonOptions,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl SseReader {
+    pub fn new(stream: ListStream, json: JsonOptions) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(0);
+        std::thread::spawn(move || {
+            for value in stream.into_iter() {
+                // The client went away
+                if sender.send(value).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Self {
+            receiver,
+            json,
+            buffer: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+impl Read for SseReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position >= self.buffer.len() {
+            self.buffer = match self.receiver.recv_timeout(KEEP_ALIVE_INTERVAL) {
+                Ok(Value::Error { error, .. }) => {
+                    return Err(std::io::Error::other(error.to_string()))
+                }
+                Ok(value) => value_to_event(&value, self.json).into_bytes(),
+                Err(RecvTimeoutError::Timeout) => b": keep-alive\n\n".to_vec(),
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            };
+            self.position = 0;
+        }
+
+        let remaining = &self.buffer[self.position..];
+        let len = remaining.len().min(buf.len());
+        buf[..len].copy_from_slice(&remaining[..len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+/// Serialize a value as a single event
+///
+/// Records with any of the `event`, `id`, `data` or `retry` fields are mapped to
+/// those SSE fields. Any other value is sent as the event data, strings verbatim
+/// and everything else as JSON.
+fn value_to_event(value: &Value, json: JsonOptions) -> String {
+    let mut event = String::new();
+
+    match value {
+        Value::Record { val, .. } if is_event_record(val) => {
+            if let Some(id) = val.get("id") {
+                push_field(&mut event, "id", &single_line(&field_to_string(id, json)));
+            }
+            if let Some(name) = val.get("event") {
+                push_field(
+                    &mut event,
+                    "event",
+                    &single_line(&field_to_string(name, json)),
+                );
+            }
+            if let Some(retry) = val.get("retry") {
+                if let Some(millis) = retry_millis(retry) {
+                    push_field(&mut event, "retry", &millis.to_string());
+                }
+            }
+            if let Some(data) = val.get("data") {
+                push_data(&mut event, data, json);
+            }
+        }
+        _ => push_data(&mut event, value, json),
+    }
+
+    event.push('\n');
+    event
+}
+
+fn is_event_record(record: &Record) -> bool {
+    ["event", "id", "data", "retry"]
+        .iter()
+        .any(|field| record.contains(field))
+}
+
+fn push_field(event: &mut String, name: &str, value: &str) {
+    event.push_str(name);
+    event.push_str(": ");
+    event.push_str(value);
+    event.push('\n');
+}
+
+/// Data may span several lines, each one gets its own `data:` field
+fn push_data(event: &mut String, data: &Value, json: JsonOptions) {
+    for line in field_to_string(data, json).split('\n') {
+        push_field(event, "data", line.strip_suffix('\r').unwrap_or(line));
+    }
+}
+
+fn field_to_string(value: &Value, json: JsonOptions) -> String {
+    match value {
+        Value::String { val, .. } => val.clone(),
+        Value::Nothing { .. } => String::new(),
+        _ => serde_json::to_string(&value_to_json(value, json)).unwrap_or_default(),
+    }
+}
+
+/// `id` and `event` must not contain line breaks
+fn single_line(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+/// Reconnection time in milliseconds, from an int (ms) or a duration
+fn retry_millis(value: &Value) -> Option<i64> {
+    match value {
+        Value::Int { val, .. } => Some(*val),
+        Value::Duration { val, .. } => Some(*val / 1_000_000),
+        _ => None,
+    }
+}
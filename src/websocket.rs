@@ -0,0 +1,120 @@
+use nu_plugin::EngineInterface;
+use nu_protocol::{engine::Closure, ListStream, PipelineData, Span, Spanned, Value};
+use tiny_http::websocket::{CLOSE_INTERNAL_ERROR, CLOSE_NORMAL};
+use tiny_http::{WebSocketMessage, WebSocketSender};
+
+use crate::json::JsonOptions;
+use crate::response::value_to_bytes;
+
+/// Handle a websocket upgrade request
+///
+/// Incoming text and binary messages are passed to the closure as a list stream
+/// on `$in`. Every value the closure produces is sent back as a frame: strings as
+/// text, binary as binary, anything else as JSON text.
+pub fn handle_websocket(
+    engine: EngineInterface,
+    span: Span,
+    closure: Spanned<Closure>,
+    request: tiny_http::Request,
+    request_value: Value,
+    json: JsonOptions,
+) {
+    let (receiver, sender) = match request.into_websocket(None) {
+        Ok(websocket) => websocket,
+        Err(request) => {
+            let response = if request.has_supported_websocket_version() {
+                tiny_http::Response::from_string("Invalid websocket handshake")
+                    .with_status_code(400)
+                    .boxed()
+            } else {
+                tiny_http::websocket::version_not_supported().boxed()
+            };
+            if let Err(e) = request.respond(response) {
+                eprintln!("Error sending error response: {}", e);
+            }
+            return;
+        }
+    };
+
+    let messages = receiver.filter_map(move |message| match message {
+        WebSocketMessage::Text(text) => Some(Value::string(text, span)),
+        WebSocketMessage::Binary(data) => Some(Value::binary(data, span)),
+        _ => None,
+    });
+    let input = PipelineData::ListStream(
+        ListStream::new(messages, span, engine.signals().clone()),
+        None,
+    );
+
+    let result = engine
+        .eval_closure_cloned_with_stream(
+            &closure,
+            vec![request_value],
+            input,
+            true,  // redirect_stdout
+            false, // redirect_stderr
+        )
+        .map_err(|err| err.to_string())
+        .and_then(|pipeline_data| send_pipeline_data(&sender, pipeline_data, json));
+
+    let closed = match result {
+        Ok(()) => sender.close(CLOSE_NORMAL, ""),
+        // The details are for the server's log, not the client
+        Err(err) => {
+            eprintln!("Error in websocket closure: {}", err);
+            sender.close(CLOSE_INTERNAL_ERROR, "internal error")
+        }
+    };
+    if let Err(e) = closed {
+        eprintln!("Error closing websocket: {}", e);
+    }
+}
+
+/// Send the closure's output as websocket frames, stopping when the client goes away
+fn send_pipeline_data(
+    sender: &WebSocketSender,
+    pipeline_data: PipelineData,
+    json: JsonOptions,
+) -> Result<(), String> {
+    match pipeline_data {
+        PipelineData::Empty => Ok(()),
+        PipelineData::Value(value, _) => send_value(sender, value, json),
+        PipelineData::ListStream(stream, _) => {
+            for value in stream.into_iter() {
+                if sender.is_closed() {
+                    break;
+                }
+                send_value(sender, value, json)?;
+            }
+            Ok(())
+        }
+        PipelineData::ByteStream(stream, _) => {
+            let Some(chunks) = stream.chunks() else {
+                return Ok(());
+            };
+            for chunk in chunks {
+                if sender.is_closed() {
+                    break;
+                }
+                send_value(sender, chunk.map_err(|err| err.to_string())?, json)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn send_value(sender: &WebSocketSender, value: Value, json: JsonOptions) -> Result<(), String> {
+    let message = match value {
+        Value::Nothing { .. } => return Ok(()),
+        Value::Error { error, .. } => return Err(error.to_string()),
+        Value::String { val, .. } => WebSocketMessage::Text(val),
+        Value::Binary { val, .. } => WebSocketMessage::Binary(val),
+        value => WebSocketMessage::Text(
+            String::from_utf8_lossy(&value_to_bytes(value, json)).into_owned(),
+        ),
+    };
+
+    // A failed write marks the sender as closed, which ends the stream above
+    let _ = sender.send(&message);
+    Ok(())
+}
@@ -1,3 +1,4 @@
+use flate2::read::GzDecoder;
 use nu_plugin_test_support::PluginTest;
 use nu_protocol::ShellError;
 use std::io::{Read, Write};
@@ -29,6 +30,12 @@ impl PluginTestServer {
         use nu_plugin_http_serve::HttpServePlugin;
 
         let mut plugin_test = PluginTest::new("http", HttpServePlugin::new().into())?;
+        // PluginTest only knows the core language; add what the test closures use
+        plugin_test
+            .add_decl(Box::new(nu_command::MetadataSet))?
+            .add_decl(Box::new(nu_command::Each))?
+            .add_decl(Box::new(nu_command::Sleep))?
+            .add_decl(Box::new(nu_command::Decode))?;
         let address = addr.to_string();
         let cmd = format!("http serve {} {}", addr, closure);
         let shutdown = Arc::new(AtomicBool::new(false));
@@ -52,14 +59,41 @@ impl PluginTestServer {
 
     /// Send an HTTP request over TCP
     fn request_tcp(&self, path: &str) -> std::io::Result<String> {
+        self.request_tcp_with_headers(path, &[])
+    }
+
+    /// Send an HTTP request over TCP with extra headers
+    fn request_tcp_with_headers(
+        &self,
+        path: &str,
+        headers: &[(&str, &str)],
+    ) -> std::io::Result<String> {
+        self.request_tcp_with_body("GET", path, headers, "")
+    }
+
+    /// Send an HTTP request over TCP with extra headers and a body
+    fn request_tcp_with_body(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &str,
+    ) -> std::io::Result<String> {
         let mut stream = TcpStream::connect(&self.address)?;
         stream.set_read_timeout(Some(Duration::from_secs(2)))?;
 
         write!(
             stream,
-            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
-            path
+            "{} {} HTTP/1.1\r\nHost: localhost\r\n",
+            method, path
         )?;
+        for (name, value) in headers {
+            write!(stream, "{}: {}\r\n", name, value)?;
+        }
+        if !body.is_empty() {
+            write!(stream, "Content-Length: {}\r\n", body.len())?;
+        }
+        write!(stream, "Connection: close\r\n\r\n{}", body)?;
 
         let mut response = String::new();
         stream.read_to_string(&mut response)?;
@@ -83,6 +117,36 @@ impl PluginTestServer {
     }
 }
 
+/// The body of a response sent with `Transfer-Encoding: chunked`, with the chunks joined
+fn chunked_body(response: &str) -> String {
+    String::from_utf8_lossy(&chunked_bytes(response.as_bytes())).into_owned()
+}
+
+/// Like `chunked_body`, for a body that isn't text
+fn chunked_bytes(response: &[u8]) -> Vec<u8> {
+    let find_crlf = |bytes: &[u8]| bytes.windows(2).position(|w| w == b"\r\n");
+    let mut rest = match response.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(head_end) => &response[head_end + 4..],
+        None => &[],
+    };
+    let mut body = Vec::new();
+    while let Some(size_end) = find_crlf(rest) {
+        let size = std::str::from_utf8(&rest[..size_end])
+            .ok()
+            .and_then(|size| usize::from_str_radix(size.trim(), 16).ok())
+            .unwrap_or(0);
+        let chunk = &rest[size_end + 2..];
+        if size == 0 || chunk.len() < size {
+            break;
+        }
+        body.extend_from_slice(&chunk[..size]);
+        rest = chunk[size..]
+            .strip_prefix(b"\r\n")
+            .unwrap_or(&chunk[size..]);
+    }
+    body
+}
+
 impl Drop for PluginTestServer {
     fn drop(&mut self) {
         // Signal shutdown
@@ -160,3 +224,439 @@ fn test_json_response() -> Result<(), ShellError> {
     assert!(response.contains(r#""method""#));
     Ok(())
 }
+
+#[test]
+fn test_accept_html() -> Result<(), ShellError> {
+    let server = PluginTestServer::new("127.0.0.1:18769", r#"{|req| [{a: 1, b: "<b>"}]}"#)?;
+
+    let response = server
+        .request_tcp_with_headers("/", &[("Accept", "text/html, */*;q=0.8")])
+        .expect("Failed to send request");
+    assert!(response.contains("HTTP/1.1 200"));
+    assert!(response.contains("text/html"));
+    assert!(response.contains("<th>a</th><th>b</th>"));
+    assert!(response.contains("<td>&lt;b&gt;</td>"));
+    Ok(())
+}
+
+#[test]
+fn test_not_acceptable() -> Result<(), ShellError> {
+    let server = PluginTestServer::new("127.0.0.1:18770", r#"{|req| [1, 2, 3]}"#)?;
+
+    let response = server
+        .request_tcp_with_headers("/", &[("Accept", "text/csv")])
+        .expect("Failed to send request");
+    assert!(response.contains("HTTP/1.1 406"));
+    assert!(response.contains("application/problem+json"));
+    assert!(response.contains("Vary: Accept"));
+    Ok(())
+}
+
+#[test]
+fn test_json_nu_types() -> Result<(), ShellError> {
+    let server = PluginTestServer::new(
+        "127.0.0.1:18771",
+        r#"{|req| {date: 2024-01-02T03:04:05+00:00, duration: 1sec, size: 2kB, bytes: 0x[01 02], range: 0..10000000000}}"#,
+    )?;
+
+    let response = server.request_tcp("/").expect("Failed to send request");
+    assert!(response.contains("HTTP/1.1 200"));
+    assert!(response.contains(r#""date":"2024-01-02T03:04:05+00:00""#));
+    assert!(response.contains(r#""duration":1000000000"#));
+    assert!(response.contains(r#""size":2000"#));
+    assert!(response.contains(r#""bytes":"AQI=""#));
+    assert!(response.contains(r#""range":{"start":0,"end":10000000000,"step":1,"inclusive":true}"#));
+    Ok(())
+}
+
+#[test]
+fn test_error_problem_json() -> Result<(), ShellError> {
+    let server = PluginTestServer::new(
+        "127.0.0.1:18772",
+        r#"{|req| error make {msg: "secret internals"}}"#,
+    )?;
+
+    let response = server.request_tcp("/").expect("Failed to send request");
+    assert!(response.contains("HTTP/1.1 500"));
+    assert!(response.contains("application/problem+json"));
+    assert!(!response.contains("secret internals"));
+    Ok(())
+}
+
+#[test]
+fn test_http_error_status() -> Result<(), ShellError> {
+    let server = PluginTestServer::new(
+        "127.0.0.1:18773",
+        r#"{|req| http error 404 "no such page"}"#,
+    )?;
+
+    let response = server.request_tcp("/").expect("Failed to send request");
+    assert!(response.contains("HTTP/1.1 404"));
+    assert!(response.contains("no such page"));
+    Ok(())
+}
+
+#[test]
+fn test_http_redirect() -> Result<(), ShellError> {
+    let server = PluginTestServer::new(
+        "127.0.0.1:18774",
+        r#"{|req| http redirect ../new --status 301}"#,
+    )?;
+
+    let response = server
+        .request_tcp("/old/page")
+        .expect("Failed to send request");
+    assert!(response.contains("HTTP/1.1 301"));
+    assert!(response.contains("Location: http://localhost/new"));
+    Ok(())
+}
+
+#[test]
+fn test_handler_timeout() -> Result<(), ShellError> {
+    let server = PluginTestServer::new(
+        "127.0.0.1:18775",
+        r#"{|req| for i in 1..10000000 { }; "too late"} --handler-timeout 100ms"#,
+    )?;
+
+    let response = server.request_tcp("/").expect("Failed to send request");
+    assert!(response.contains("HTTP/1.1 504"));
+    assert!(!response.contains("too late"));
+    Ok(())
+}
+
+#[test]
+fn test_max_url_length() -> Result<(), ShellError> {
+    let server = PluginTestServer::new("127.0.0.1:18776", r#"{|req| "ok"} --max-url-length 16"#)?;
+
+    let response = server
+        .request_tcp("/short")
+        .expect("Failed to send request");
+    assert!(response.contains("HTTP/1.1 200"));
+
+    let long_path = format!("/{}", "a".repeat(100));
+    let response = server
+        .request_tcp(&long_path)
+        .expect("Failed to send request");
+    assert!(response.contains("HTTP/1.1 414"));
+    Ok(())
+}
+
+#[test]
+fn test_response_metadata() -> Result<(), ShellError> {
+    let server = PluginTestServer::new(
+        "127.0.0.1:18777",
+        r#"{|req| "gone" | metadata set --merge {'http.response': {status: 410, headers: {x-reason: [a, b]}}}}"#,
+    )?;
+
+    let response = server.request_tcp("/").expect("Failed to send request");
+    assert!(response.starts_with("HTTP/1.1 410"));
+    assert!(response.contains("x-reason: a\r\n"));
+    assert!(response.contains("x-reason: b\r\n"));
+    assert!(response.ends_with("gone"));
+    Ok(())
+}
+
+#[test]
+fn test_decoded_path_and_query() -> Result<(), ShellError> {
+    let server = PluginTestServer::new(
+        "127.0.0.1:18778",
+        r#"{|req| $"($req.path)|($req.raw_path)|($req.query.name)|($req.query.debug)"}"#,
+    )?;
+
+    let response = server
+        .request_tcp("/a%20b/c?name=J%C3%BCrgen+X&debug")
+        .expect("Failed to send request");
+    assert!(response.contains("HTTP/1.1 200"));
+    assert!(response.ends_with("/a b/c|/a%20b/c|Jürgen X|true"));
+    Ok(())
+}
+
+#[test]
+fn test_undecodable_body() -> Result<(), ShellError> {
+    let server = PluginTestServer::new("127.0.0.1:18779", r#"{|req| $in.name} --decode-body"#)?;
+
+    let json = [("Content-Type", "application/json")];
+    let response = server
+        .request_tcp_with_body("POST", "/", &json, r#"{"name": "nu"}"#)
+        .expect("Failed to send request");
+    assert!(response.contains("HTTP/1.1 200"));
+    assert!(response.ends_with("nu"));
+
+    let response = server
+        .request_tcp_with_body("POST", "/", &json, r#"{"name": "#)
+        .expect("Failed to send request");
+    assert!(response.contains("HTTP/1.1 400"));
+    Ok(())
+}
+
+#[test]
+fn test_request_cookies() -> Result<(), ShellError> {
+    let server = PluginTestServer::new(
+        "127.0.0.1:18780",
+        r#"{|req| $"($req.cookies.theme)|($req.cookies.session)" | metadata set --merge {'http.response': {cookies: {seen: {value: "1", http_only: true}}}}}"#,
+    )?;
+
+    let response = server
+        .request_tcp_with_headers("/", &[("Cookie", r#"theme=dark; session="abc""#)])
+        .expect("Failed to send request");
+    assert!(response.contains("HTTP/1.1 200"));
+    assert!(response.contains("Set-Cookie: seen=1; HttpOnly\r\n"));
+    assert!(response.ends_with("dark|abc"));
+    Ok(())
+}
+
+#[test]
+fn test_invalid_set_cookie() -> Result<(), ShellError> {
+    let server = PluginTestServer::new(
+        "127.0.0.1:18781",
+        r#"{|req| "ok" | metadata set --merge {'http.response': {cookies: {session: "a; Domain=evil"}}}}"#,
+    )?;
+
+    let response = server.request_tcp("/").expect("Failed to send request");
+    assert!(response.contains("HTTP/1.1 500"));
+    assert!(!response.contains("Set-Cookie"));
+    Ok(())
+}
+
+#[test]
+fn test_conditional_requests() -> Result<(), ShellError> {
+    let server = PluginTestServer::new(
+        "127.0.0.1:18782",
+        r#"{|req| if $req.path == "/stream" { [a b] | each {|x| $x} } else { "hello" | metadata set --merge {'http.response': {etag: "v1", headers: {x-kept: "yes"}}} } }"#,
+    )?;
+
+    let response = server
+        .request_tcp_with_headers("/", &[("If-None-Match", r#""v1""#)])
+        .expect("Failed to send request");
+    assert!(response.starts_with("HTTP/1.1 304"));
+    assert!(response.contains("ETag: \"v1\"\r\n"));
+    assert!(response.contains("x-kept: yes\r\n"));
+    assert!(!response.contains("hello"));
+
+    let response = server
+        .request_tcp_with_headers("/", &[("If-Match", r#""v2""#)])
+        .expect("Failed to send request");
+    assert!(response.starts_with("HTTP/1.1 412"));
+    assert!(response.contains("x-kept: yes\r\n"));
+
+    // `*` matches any current representation, with or without an ETag
+    let response = server
+        .request_tcp_with_headers("/", &[("If-Match", "*")])
+        .expect("Failed to send request");
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(response.ends_with("hello"));
+
+    let response = server
+        .request_tcp_with_headers("/stream", &[("If-Match", "*")])
+        .expect("Failed to send request");
+    assert!(response.starts_with("HTTP/1.1 200"));
+    Ok(())
+}
+
+#[test]
+fn test_list_stream_not_acceptable() -> Result<(), ShellError> {
+    let server = PluginTestServer::new("127.0.0.1:18784", r#"{|req| [1 2 3] | each {|x| $x}}"#)?;
+
+    let response = server
+        .request_tcp_with_headers("/", &[("Accept", "text/csv")])
+        .expect("Failed to send request");
+    assert!(response.contains("HTTP/1.1 406"));
+
+    let response = server
+        .request_tcp_with_headers("/", &[("Accept", "text/html")])
+        .expect("Failed to send request");
+    assert!(response.contains("HTTP/1.1 200"));
+    assert!(response.contains("<li>2</li>"));
+    Ok(())
+}
+
+#[test]
+fn test_list_stream_framing() -> Result<(), ShellError> {
+    let server =
+        PluginTestServer::new("127.0.0.1:18785", r#"{|req| [1 2 3] | each {|x| {n: $x}}}"#)?;
+
+    let response = server.request_tcp("/").expect("Failed to send request");
+    assert!(response.contains("HTTP/1.1 200"));
+    assert!(response.contains("Content-Type: application/json"));
+    assert_eq!(chunked_body(&response), r#"[{"n":1},{"n":2},{"n":3}]"#);
+
+    let response = server
+        .request_tcp_with_headers("/", &[("Accept", "application/x-ndjson")])
+        .expect("Failed to send request");
+    assert!(response.contains("Content-Type: application/x-ndjson"));
+    assert_eq!(chunked_body(&response), "{\"n\":1}\n{\"n\":2}\n{\"n\":3}\n");
+    Ok(())
+}
+
+#[test]
+fn test_max_concurrency_queue_full() -> Result<(), ShellError> {
+    let server = PluginTestServer::new(
+        "127.0.0.1:18786",
+        r#"{|req| sleep 500ms; "done"} --max-concurrency 1 --max-queue 1 --retry-after 3sec"#,
+    )?;
+
+    // The first request is handled, the second waits in the queue, the third finds it full
+    thread::scope(|scope| {
+        let handled = scope.spawn(|| server.request_tcp("/1"));
+        thread::sleep(Duration::from_millis(150));
+        let queued = scope.spawn(|| server.request_tcp("/2"));
+        thread::sleep(Duration::from_millis(150));
+
+        let response = server.request_tcp("/3").expect("Failed to send request");
+        assert!(response.starts_with("HTTP/1.1 503"));
+        assert!(response.contains("Retry-After: 3\r\n"));
+
+        for response in [handled, queued] {
+            let response = response.join().unwrap().expect("Failed to send request");
+            assert!(response.starts_with("HTTP/1.1 200"));
+            assert!(response.ends_with("done"));
+        }
+    });
+    Ok(())
+}
+
+#[test]
+fn test_server_sent_events() -> Result<(), ShellError> {
+    let server = PluginTestServer::new(
+        "127.0.0.1:18787",
+        r#"{|req| [{event: greet, id: 1, retry: 2sec, data: "a\nb"} {data: {n: 1}} "plain"] | each {|x| $x}}"#,
+    )?;
+
+    let response = server
+        .request_tcp_with_headers("/", &[("Accept", "text/event-stream")])
+        .expect("Failed to send request");
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(response.contains("Content-Type: text/event-stream"));
+    assert_eq!(
+        chunked_body(&response),
+        "id: 1\nevent: greet\nretry: 2000\ndata: a\ndata: b\n\ndata: {\"n\":1}\n\ndata: plain\n\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_repeated_headers() -> Result<(), ShellError> {
+    let server = PluginTestServer::new(
+        "127.0.0.1:18788",
+        r#"{|req| {tag: $req.headers.x-tag, single: $req.headers.x-single}}"#,
+    )?;
+
+    let response = server
+        .request_tcp_with_headers("/", &[("X-Tag", "a"), ("x-TAG", "b"), ("X-Single", "one")])
+        .expect("Failed to send request");
+    assert!(response.contains("HTTP/1.1 200"));
+    assert!(response.ends_with(r#"{"tag":["a","b"],"single":"one"}"#));
+    Ok(())
+}
+
+const MULTIPART_BODY: &str = "--XyZ\r\n\
+    Content-Disposition: form-data; name=\"field\"\r\n\r\n\
+    hello\r\n\
+    --XyZ\r\n\
+    Content-Disposition: form-data; name=\"file\"; filename=\"naive.txt\"; filename*=UTF-8''na%C3%AFve.txt\r\n\
+    Content-Type: text/plain\r\n\r\n\
+    file content\r\n\
+    --XyZ--\r\n";
+
+#[test]
+fn test_multipart_buffered() -> Result<(), ShellError> {
+    let server = PluginTestServer::new(
+        "127.0.0.1:18789",
+        r#"{|req| http multipart | each {|part| {name: $part.name, filename: $part.filename, type: $part.content_type, text: ($part.content | describe)}}}"#,
+    )?;
+
+    let response = server
+        .request_tcp_with_body(
+            "POST",
+            "/",
+            &[("Content-Type", "multipart/form-data; boundary=XyZ")],
+            MULTIPART_BODY,
+        )
+        .expect("Failed to send request");
+    assert!(response.contains("HTTP/1.1 200"));
+    assert_eq!(
+        chunked_body(&response),
+        r#"[{"name":"field","filename":null,"type":null,"text":"string"},{"name":"file","filename":"naïve.txt","type":"text/plain","text":"binary"}]"#
+    );
+    Ok(())
+}
+
+#[test]
+fn test_multipart_streamed() -> Result<(), ShellError> {
+    let server = PluginTestServer::new(
+        "127.0.0.1:18790",
+        r#"{|req| http multipart {|part| $"($part.name): ($in | decode utf-8)"}}"#,
+    )?;
+
+    let response = server
+        .request_tcp_with_body(
+            "POST",
+            "/",
+            &[("Content-Type", "multipart/form-data; boundary=XyZ")],
+            MULTIPART_BODY,
+        )
+        .expect("Failed to send request");
+    assert!(response.contains("HTTP/1.1 200"));
+    assert!(response.ends_with(r#"["field: hello","file: file content"]"#));
+    Ok(())
+}
+
+#[test]
+fn test_compression() -> Result<(), ShellError> {
+    let body = "compress me, compress me, compress me";
+    let _server = PluginTestServer::new(
+        "127.0.0.1:18791",
+        &format!(r#"{{|req| "{body}"}} --compress --compress-min-size 1b"#),
+    )?;
+
+    let mut stream = TcpStream::connect("127.0.0.1:18791").expect("Failed to connect");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .expect("Failed to set timeout");
+    write!(
+        stream,
+        "GET / HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: gzip\r\nConnection: close\r\n\r\n"
+    )
+    .expect("Failed to send request");
+    let mut response = Vec::new();
+    stream
+        .read_to_end(&mut response)
+        .expect("Failed to read response");
+
+    let head = String::from_utf8_lossy(&response);
+    assert!(head.starts_with("HTTP/1.1 200"));
+    assert!(head.contains("Content-Encoding: gzip\r\n"));
+    assert!(head.contains("Vary: Accept-Encoding\r\n"));
+
+    let mut decoded = String::new();
+    GzDecoder::new(&chunked_bytes(&response)[..])
+        .read_to_string(&mut decoded)
+        .expect("Failed to decode the gzip body");
+    assert_eq!(decoded, body);
+    Ok(())
+}
+
+#[test]
+fn test_body_timeout() -> Result<(), ShellError> {
+    let _server = PluginTestServer::new(
+        "127.0.0.1:18792",
+        r#"{|req| $in | decode utf-8} --body-timeout 200ms"#,
+    )?;
+
+    // Promise a longer body than is sent, then stall
+    let mut stream = TcpStream::connect("127.0.0.1:18792").expect("Failed to connect");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .expect("Failed to set timeout");
+    write!(
+        stream,
+        "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 100\r\nConnection: close\r\n\r\npartial"
+    )
+    .expect("Failed to send request");
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .expect("Failed to read response");
+    assert!(response.starts_with("HTTP/1.1 408"));
+    Ok(())
+}
@@ -6,11 +6,15 @@ use std::io::{BufReader, BufWriter, ErrorKind, Read};
 
 use std::net::SocketAddr;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::common::{HTTPVersion, Method};
+use crate::connection::Connection;
 use crate::util::RefinedTcpStream;
 use crate::util::{SequentialReader, SequentialReaderBuilder, SequentialWriterBuilder};
-use crate::Request;
+use crate::{Header, Request, ServerLimits};
 
 /// A ClientConnection is an object that will store a socket to a client
 /// and return Request objects.
@@ -34,6 +38,18 @@ pub struct ClientConnection {
 
     // true if the connection goes through SSL
     secure: bool,
+
+    // set when the server is draining: no new request is read from the connection
+    draining: Arc<AtomicBool>,
+
+    // the underlying socket, to change its read timeout between requests
+    socket: Option<Arc<Connection>>,
+
+    // timeouts and limits of the connection
+    limits: ServerLimits,
+
+    // number of requests read from the connection so far
+    requests_read: usize,
 }
 
 /// Error that can happen when reading a request.
@@ -43,14 +59,28 @@ enum ReadError {
     WrongHeader(HTTPVersion),
     /// the client sent an unrecognized `Expect` header
     ExpectationFailed(HTTPVersion),
+    /// the client didn't start a new request in time
+    IdleTimeout,
+    /// the request line is longer than the limits allow
+    UriTooLong,
+    /// the headers are larger or more numerous than the limits allow
+    HeadersTooLarge(HTTPVersion),
+    /// the Content-Length is above the body size limit
+    PayloadTooLarge(HTTPVersion),
     ReadIoError(IoError),
 }
 
 impl ClientConnection {
     /// Creates a new `ClientConnection` that takes ownership of the `TcpStream`.
+    ///
+    /// `socket` is a clone of the underlying socket, used to apply the timeouts of
+    /// `limits`. Its read timeout must already be the header read timeout.
     pub fn new(
         write_socket: RefinedTcpStream,
         mut read_socket: RefinedTcpStream,
+        socket: Option<Connection>,
+        draining: Arc<AtomicBool>,
+        limits: ServerLimits,
     ) -> ClientConnection {
         let remote_addr = read_socket.peer_addr();
         let secure = read_socket.secure();
@@ -65,6 +95,10 @@ impl ClientConnection {
             next_header_source: first_header,
             no_more_requests: false,
             secure,
+            draining,
+            socket: socket.map(Arc::new),
+            limits,
+            requests_read: 0,
         }
     }
 
@@ -73,56 +107,142 @@ impl ClientConnection {
         self.secure
     }
 
+    fn set_read_timeout(&self, timeout: Option<Duration>) {
+        if let Some(socket) = &self.socket {
+            socket.set_read_timeout(timeout).ok();
+        }
+    }
+
+    /// Reads the first byte of the next request.
+    ///
+    /// Fails with `IdleTimeout` if the client sends nothing within the keep-alive timeout
+    /// (the header read timeout for the first request), counted from the moment the
+    /// previous response is sent.
+    fn wait_for_request(&mut self) -> Result<u8, ReadError> {
+        let mut byte = [0];
+        loop {
+            match self.next_header_source.read(&mut byte) {
+                Ok(0) => {
+                    let err = IoError::new(ErrorKind::ConnectionAborted, "Unexpected EOF");
+                    return Err(ReadError::ReadIoError(err));
+                }
+                Ok(_) => return Ok(byte[0]),
+                Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+                // the previous response is still being prepared
+                Err(ref err) if is_timeout(err) && self.sink.has_pending_writer() => continue,
+                Err(ref err) if is_timeout(err) => return Err(ReadError::IdleTimeout),
+                Err(err) => return Err(ReadError::ReadIoError(err)),
+            }
+        }
+    }
+
     /// Reads the next line from self.next_header_source.
     ///
-    /// Reads until `CRLF` is reached. The next read will start
-    ///  at the first byte of the new line.
-    fn read_next_line(&mut self) -> IoResult<AsciiString> {
-        let mut buf = Vec::new();
-        let mut prev_byte_was_cr = false;
+    /// Reads until `CRLF` is reached, the line starting with the bytes in `buf`. The next
+    ///  read will start at the first byte of the new line. Fails with a `TimedOut` error
+    ///  once `deadline` is passed, and with `too_long` if the line (without `CRLF`) is
+    ///  longer than `limit`.
+    fn read_next_line(
+        &mut self,
+        mut buf: Vec<u8>,
+        deadline: Option<Instant>,
+        limit: Option<usize>,
+        too_long: ReadError,
+    ) -> Result<AsciiString, ReadError> {
+        let mut prev_byte_was_cr = buf.last() == Some(&b'\r');
 
         loop {
+            if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                let err = IoError::new(ErrorKind::TimedOut, "Request head took too long");
+                return Err(ReadError::ReadIoError(err));
+            }
+
             let byte = self.next_header_source.by_ref().bytes().next();
 
             let byte = match byte {
-                Some(b) => b?,
-                None => return Err(IoError::new(ErrorKind::ConnectionAborted, "Unexpected EOF")),
+                Some(b) => b.map_err(ReadError::ReadIoError)?,
+                None => {
+                    let err = IoError::new(ErrorKind::ConnectionAborted, "Unexpected EOF");
+                    return Err(ReadError::ReadIoError(err));
+                }
             };
 
             if byte == b'\n' && prev_byte_was_cr {
                 buf.pop(); // removing the '\r'
-                return AsciiString::from_ascii(buf)
-                    .map_err(|_| IoError::new(ErrorKind::InvalidInput, "Header is not in ASCII"));
+                return AsciiString::from_ascii(buf).map_err(|_| {
+                    let err = IoError::new(ErrorKind::InvalidInput, "Header is not in ASCII");
+                    ReadError::ReadIoError(err)
+                });
             }
 
             prev_byte_was_cr = byte == b'\r';
 
             buf.push(byte);
+
+            // the '\r' of the CRLF doesn't count
+            if limit.map_or(false, |limit| buf.len() > limit + 1) {
+                return Err(too_long);
+            }
         }
     }
 
     /// Reads a request from the stream.
     /// Blocks until the header has been read.
     fn read(&mut self) -> Result<Request, ReadError> {
+        let first_byte = self.wait_for_request()?;
+
+        // the rest of the head must arrive within the header read timeout
+        let header_read_timeout = self.limits.header_read_timeout;
+        self.set_read_timeout(header_read_timeout);
+        let deadline = header_read_timeout.map(|timeout| Instant::now() + timeout);
+
+        // the request line and headers share the header size limit
+        let mut head_bytes_left = self.limits.max_header_bytes;
+
         let (method, path, version, headers) = {
             // reading the request line
             let (method, path, version) = {
-                let line = self.read_next_line().map_err(ReadError::ReadIoError)?;
-
-                parse_request_line(
+                let max_url_length = self.limits.max_url_length;
+                let limit = match (
+                    head_bytes_left,
+                    max_url_length.map(|max| max + MAX_REQUEST_LINE_OVERHEAD),
+                ) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    (a, b) => a.or(b),
+                };
+                let line =
+                    self.read_next_line(vec![first_byte], deadline, limit, ReadError::UriTooLong)?;
+                head_bytes_left = head_bytes_left.map(|left| left.saturating_sub(line.len() + 2));
+
+                let (method, path, version) = parse_request_line(
                     line.as_str().trim(), // TODO: remove this conversion
-                )?
+                )?;
+                if max_url_length.map_or(false, |max| path.len() > max) {
+                    return Err(ReadError::UriTooLong);
+                }
+                (method, path, version)
             };
 
             // getting all headers
             let headers = {
                 let mut headers = Vec::new();
                 loop {
-                    let line = self.read_next_line().map_err(ReadError::ReadIoError)?;
+                    let too_large = ReadError::HeadersTooLarge(version.clone());
+                    let line =
+                        self.read_next_line(Vec::new(), deadline, head_bytes_left, too_large)?;
+                    head_bytes_left =
+                        head_bytes_left.map(|left| left.saturating_sub(line.len() + 2));
 
                     if line.is_empty() {
                         break;
                     };
+                    if self
+                        .limits
+                        .max_header_count
+                        .map_or(false, |max| headers.len() >= max)
+                    {
+                        return Err(ReadError::HeadersTooLarge(version));
+                    }
                     headers.push(match FromStr::from_str(line.as_str().trim()) {
                         // TODO: remove this conversion
                         Ok(h) => h,
@@ -143,6 +263,23 @@ impl ClientConnection {
         let mut data_source = self.source.next().unwrap();
         std::mem::swap(&mut self.next_header_source, &mut data_source);
 
+        // the body is read with the body read timeout, and upgraded connections without any
+        let upgrade = headers.iter().any(|h: &Header| {
+            h.field.equiv("Connection") && h.value.as_str().to_ascii_lowercase().contains("upgrade")
+        });
+        self.set_read_timeout(if upgrade {
+            None
+        } else {
+            self.limits.body_read_timeout
+        });
+        let body_timed_out = Arc::new(AtomicBool::new(false));
+        let data_source = BodySource {
+            inner: data_source,
+            socket: self.socket.clone(),
+            keep_alive_timeout: self.limits.keep_alive_timeout,
+            timed_out: body_timed_out.clone(),
+        };
+
         // building the next reader
         let request = crate::request::new_request(
             self.secure,
@@ -153,7 +290,12 @@ impl ClientConnection {
             *self.remote_addr.as_ref().unwrap(),
             data_source,
             writer,
+            self.limits.max_body_size,
         )
+        .map(|rq| {
+            rq.with_draining(self.draining.clone())
+                .with_body_timed_out(body_timed_out)
+        })
         .map_err(|e| {
             use crate::request;
             match e {
@@ -161,6 +303,9 @@ impl ClientConnection {
                 request::RequestCreationError::ExpectationFailed => {
                     ReadError::ExpectationFailed(version)
                 }
+                request::RequestCreationError::PayloadTooLarge => {
+                    ReadError::PayloadTooLarge(version)
+                }
             }
         })?;
 
@@ -179,7 +324,7 @@ impl Iterator for ClientConnection {
 
         // the client sent a "connection: close" header in this previous request
         //  or is using HTTP 1.0, meaning that no new request will come
-        if self.no_more_requests {
+        if self.no_more_requests || self.draining.load(Ordering::Relaxed) {
             return None;
         }
 
@@ -203,7 +348,32 @@ impl Iterator for ClientConnection {
                                  // se we have to close
                 }
 
-                Err(ReadError::ReadIoError(ref err)) if err.kind() == ErrorKind::TimedOut => {
+                Err(ReadError::IdleTimeout) => return None,
+
+                Err(ReadError::UriTooLong) => {
+                    let writer = self.sink.next().unwrap();
+                    let response = Response::new_empty(StatusCode(414));
+                    response
+                        .raw_print(writer, HTTPVersion(1, 1), &[], false, None)
+                        .ok();
+                    return None; // the rest of the request line is unread
+                }
+
+                Err(ReadError::HeadersTooLarge(ver)) => {
+                    let writer = self.sink.next().unwrap();
+                    let response = Response::new_empty(StatusCode(431));
+                    response.raw_print(writer, ver, &[], false, None).ok();
+                    return None; // the rest of the headers are unread
+                }
+
+                Err(ReadError::PayloadTooLarge(ver)) => {
+                    let writer = self.sink.next().unwrap();
+                    let response = Response::new_empty(StatusCode(413));
+                    response.raw_print(writer, ver, &[], false, None).ok();
+                    return None; // the body is unread
+                }
+
+                Err(ReadError::ReadIoError(ref err)) if is_timeout(err) => {
                     // request timeout
                     let writer = self.sink.next().unwrap();
                     let response = Response::new_empty(StatusCode(408));
@@ -225,6 +395,16 @@ impl Iterator for ClientConnection {
                 Ok(rq) => rq,
             };
 
+            // the server started draining while we were waiting for this request
+            if self.draining.load(Ordering::Relaxed) {
+                rq.respond(
+                    Response::from_string("Server is shutting down".to_owned())
+                        .with_status_code(StatusCode(503)),
+                )
+                .ok();
+                return None;
+            }
+
             // checking HTTP version
             if *rq.http_version() > (1, 1) {
                 let writer = self.sink.next().unwrap();
@@ -259,12 +439,64 @@ impl Iterator for ClientConnection {
                 _ => (),
             };
 
+            // closing the connection after its last allowed request
+            self.requests_read += 1;
+            let rq = if self
+                .limits
+                .max_requests_per_connection
+                .map_or(false, |max| self.requests_read >= max)
+            {
+                self.no_more_requests = true;
+                rq.with_connection_close()
+            } else {
+                rq
+            };
+
             // returning the request
             return Some(rq);
         }
     }
 }
 
+/// Room for the method, spaces and HTTP version around the URL in a request line.
+const MAX_REQUEST_LINE_OVERHEAD: usize = 32;
+
+/// Reader of a request's body, which gives the socket the keep-alive timeout back once
+/// the body is dropped, as the next read waits for a new request.
+///
+/// `timed_out` is set when a read fails with the body read timeout.
+struct BodySource<R> {
+    inner: R,
+    socket: Option<Arc<Connection>>,
+    keep_alive_timeout: Option<Duration>,
+    timed_out: Arc<AtomicBool>,
+}
+
+impl<R: Read> Read for BodySource<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        self.inner.read(buf).map_err(|err| {
+            if is_timeout(&err) {
+                self.timed_out.store(true, Ordering::Relaxed);
+            }
+            err
+        })
+    }
+}
+
+impl<R> Drop for BodySource<R> {
+    fn drop(&mut self) {
+        if let Some(socket) = &self.socket {
+            socket.set_read_timeout(self.keep_alive_timeout).ok();
+        }
+    }
+}
+
+/// Returns true if the error is a socket timeout, which is reported as `WouldBlock` on
+/// Unix and `TimedOut` on Windows.
+fn is_timeout(err: &IoError) -> bool {
+    matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
 /// Parses a "HTTP/1.1" string.
 fn parse_http_version(version: &str) -> Result<HTTPVersion, ReadError> {
     let (major, minor) = match version {
@@ -0,0 +1,342 @@
+//! Negotiated response compression.
+//!
+//! The encoders are behind cargo features: `compression-gzip` (gzip and deflate),
+//! `compression-brotli` and `compression-zstd`, or `compression` for all of them. Without
+//! any of them, `Response::with_compression` only adds the `Vary` header.
+
+use std::io::Error as IoError;
+use std::io::Result as IoResult;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::util::parse_header_value;
+
+/// Settings for `Response::with_compression`.
+#[derive(Debug, Clone)]
+pub struct CompressionConfig {
+    /// Bodies of known length smaller than this are sent uncompressed. Streamed bodies
+    /// of unknown length are always compressed.
+    pub min_size: usize,
+    /// Content types that are compressed. An entry ending with `/` matches a whole type
+    /// (`text/`), an entry starting with `+` matches a structured syntax suffix (`+json`),
+    /// and anything else must match the media type exactly.
+    pub content_types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> CompressionConfig {
+        CompressionConfig {
+            min_size: 1024,
+            content_types: [
+                "text/",
+                "application/json",
+                "application/javascript",
+                "application/xml",
+                "application/x-ndjson",
+                "image/svg+xml",
+                "+json",
+                "+xml",
+            ]
+            .iter()
+            .map(|t| t.to_string())
+            .collect(),
+        }
+    }
+}
+
+impl CompressionConfig {
+    /// Returns true if a body of this content type should be compressed.
+    pub fn compresses(&self, content_type: &str) -> bool {
+        let media_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase();
+
+        self.content_types.iter().any(|allowed| {
+            let allowed = allowed.to_ascii_lowercase();
+            if allowed.ends_with('/') {
+                media_type.starts_with(&allowed)
+            } else if allowed.starts_with('+') {
+                media_type.ends_with(&allowed)
+            } else {
+                media_type == allowed
+            }
+        })
+    }
+}
+
+/// A content coding supported by this build.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentEncoding {
+    Gzip,
+    Deflate,
+    Brotli,
+    Zstd,
+}
+
+impl ContentEncoding {
+    /// Codings compiled in, in order of preference when the client rates them equally.
+    pub fn available() -> &'static [ContentEncoding] {
+        &[
+            #[cfg(feature = "compression-zstd")]
+            ContentEncoding::Zstd,
+            #[cfg(feature = "compression-brotli")]
+            ContentEncoding::Brotli,
+            #[cfg(feature = "compression-gzip")]
+            ContentEncoding::Gzip,
+            #[cfg(feature = "compression-gzip")]
+            ContentEncoding::Deflate,
+        ]
+    }
+
+    /// The name used in `Accept-Encoding` and `Content-Encoding`.
+    pub fn name(self) -> &'static str {
+        match self {
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Deflate => "deflate",
+            ContentEncoding::Brotli => "br",
+            ContentEncoding::Zstd => "zstd",
+        }
+    }
+
+    /// Picks the coding to use from the value of an `Accept-Encoding` header.
+    ///
+    /// The highest q-value wins, `*` stands for any coding not listed, and `q=0` rules
+    /// a coding out. Returns `None` if the body should be sent as-is.
+    pub fn negotiate(accept_encoding: &str) -> Option<ContentEncoding> {
+        Self::negotiate_from(accept_encoding, Self::available())
+    }
+
+    fn negotiate_from(
+        accept_encoding: &str,
+        available: &[ContentEncoding],
+    ) -> Option<ContentEncoding> {
+        let accepted = parse_header_value(accept_encoding);
+        let quality = |name: &str| {
+            accepted
+                .iter()
+                .find(|(coding, _)| coding.eq_ignore_ascii_case(name))
+                .or_else(|| accepted.iter().find(|(coding, _)| *coding == "*"))
+                .map(|(_, q)| *q)
+        };
+
+        let mut best: Option<(ContentEncoding, f32)> = None;
+        for &encoding in available {
+            let q = match quality(encoding.name()) {
+                Some(q) if q > 0.0 => q,
+                _ => continue,
+            };
+            if best.map_or(true, |(_, best_q)| q > best_q) {
+                best = Some((encoding, q));
+            }
+        }
+
+        // the client may prefer the body as-is
+        let identity = quality("identity").unwrap_or(0.0);
+        best.filter(|(_, q)| *q >= identity)
+            .map(|(encoding, _)| encoding)
+    }
+}
+
+/// `Write` end of the encoders, so that their output can be taken as it is produced.
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+enum Encoder {
+    #[cfg(feature = "compression-gzip")]
+    Gzip(flate2::write::GzEncoder<SharedBuffer>),
+    #[cfg(feature = "compression-gzip")]
+    Deflate(flate2::write::ZlibEncoder<SharedBuffer>),
+    #[cfg(feature = "compression-brotli")]
+    Brotli(Box<brotli::CompressorWriter<SharedBuffer>>),
+    #[cfg(feature = "compression-zstd")]
+    Zstd(zstd::stream::write::Encoder<'static, SharedBuffer>),
+}
+
+impl Encoder {
+    #[allow(unused_variables)]
+    fn new(encoding: ContentEncoding, output: SharedBuffer) -> IoResult<Encoder> {
+        match encoding {
+            #[cfg(feature = "compression-gzip")]
+            ContentEncoding::Gzip => Ok(Encoder::Gzip(flate2::write::GzEncoder::new(
+                output,
+                flate2::Compression::default(),
+            ))),
+            #[cfg(feature = "compression-gzip")]
+            ContentEncoding::Deflate => Ok(Encoder::Deflate(flate2::write::ZlibEncoder::new(
+                output,
+                flate2::Compression::default(),
+            ))),
+            #[cfg(feature = "compression-brotli")]
+            ContentEncoding::Brotli => Ok(Encoder::Brotli(Box::new(
+                brotli::CompressorWriter::new(output, 4096, 5, 22),
+            ))),
+            #[cfg(feature = "compression-zstd")]
+            ContentEncoding::Zstd => {
+                Ok(Encoder::Zstd(zstd::stream::write::Encoder::new(output, 3)?))
+            }
+            #[allow(unreachable_patterns)]
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "content coding not compiled in",
+            )),
+        }
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match *self {
+            #[cfg(feature = "compression-gzip")]
+            Encoder::Gzip(ref mut e) => e,
+            #[cfg(feature = "compression-gzip")]
+            Encoder::Deflate(ref mut e) => e,
+            #[cfg(feature = "compression-brotli")]
+            Encoder::Brotli(ref mut e) => &mut **e,
+            #[cfg(feature = "compression-zstd")]
+            Encoder::Zstd(ref mut e) => e,
+        }
+    }
+
+    /// Writes the end of the compressed stream.
+    fn finish(self) -> IoResult<()> {
+        match self {
+            #[cfg(feature = "compression-gzip")]
+            Encoder::Gzip(e) => e.finish().map(drop),
+            #[cfg(feature = "compression-gzip")]
+            Encoder::Deflate(e) => e.finish().map(drop),
+            #[cfg(feature = "compression-brotli")]
+            Encoder::Brotli(e) => {
+                e.into_inner();
+                Ok(())
+            }
+            #[cfg(feature = "compression-zstd")]
+            Encoder::Zstd(e) => e.finish().map(drop),
+        }
+    }
+}
+
+/// Compresses the data of a reader as it is read.
+///
+/// With `flush_each_read`, everything read from the inner reader so far is flushed out of
+/// the encoder before returning, so that streamed responses aren't held back.
+pub struct CompressReader<R> {
+    reader: R,
+    encoder: Option<Encoder>,
+    output: SharedBuffer,
+    pending: Vec<u8>,
+    position: usize,
+    flush_each_read: bool,
+}
+
+impl<R: Read> CompressReader<R> {
+    /// Compresses `reader` with `encoding`.
+    ///
+    /// If the encoder can't be set up, the reader is given back along with the error.
+    pub fn new(
+        reader: R,
+        encoding: ContentEncoding,
+        flush_each_read: bool,
+    ) -> Result<CompressReader<R>, (R, IoError)> {
+        let output = SharedBuffer::default();
+        let encoder = match Encoder::new(encoding, output.clone()) {
+            Ok(encoder) => encoder,
+            Err(err) => return Err((reader, err)),
+        };
+        Ok(CompressReader {
+            reader,
+            encoder: Some(encoder),
+            output,
+            pending: Vec::new(),
+            position: 0,
+            flush_each_read,
+        })
+    }
+}
+
+impl<R: Read> Read for CompressReader<R> {
+    // without any encoder compiled in, `Encoder` has no values
+    #[allow(unreachable_code)]
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let mut input = [0; 8192];
+        while self.position >= self.pending.len() {
+            let encoder = match self.encoder.as_mut() {
+                Some(encoder) => encoder,
+                None => return Ok(0),
+            };
+
+            match self.reader.read(&mut input)? {
+                0 => self.encoder.take().unwrap().finish()?,
+                read => {
+                    encoder.writer().write_all(&input[..read])?;
+                    if self.flush_each_read {
+                        encoder.writer().flush()?;
+                    }
+                }
+            }
+
+            self.pending = std::mem::take(&mut *self.output.0.lock().unwrap());
+            self.position = 0;
+        }
+
+        let remaining = &self.pending[self.position..];
+        let len = remaining.len().min(buf.len());
+        buf[..len].copy_from_slice(&remaining[..len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CompressionConfig, ContentEncoding};
+
+    const ALL: &[ContentEncoding] = &[
+        ContentEncoding::Zstd,
+        ContentEncoding::Brotli,
+        ContentEncoding::Gzip,
+        ContentEncoding::Deflate,
+    ];
+
+    #[test]
+    fn negotiation() {
+        let negotiate = |accept: &str| ContentEncoding::negotiate_from(accept, ALL);
+
+        assert_eq!(
+            negotiate("gzip, deflate, br"),
+            Some(ContentEncoding::Brotli)
+        );
+        assert_eq!(
+            negotiate("gzip;q=1.0, br;q=0.5"),
+            Some(ContentEncoding::Gzip)
+        );
+        assert_eq!(negotiate("deflate"), Some(ContentEncoding::Deflate));
+        assert_eq!(negotiate("*"), Some(ContentEncoding::Zstd));
+        assert_eq!(negotiate("*, zstd;q=0"), Some(ContentEncoding::Brotli));
+        assert_eq!(negotiate("identity"), None);
+        assert_eq!(negotiate("gzip;q=0.5, identity"), None);
+        assert_eq!(negotiate("compress"), None);
+        assert_eq!(negotiate(""), None);
+    }
+
+    #[test]
+    fn content_type_allowlist() {
+        let config = CompressionConfig::default();
+        assert!(config.compresses("text/html; charset=utf-8"));
+        assert!(config.compresses("application/json"));
+        assert!(config.compresses("application/problem+json"));
+        assert!(!config.compresses("image/png"));
+        assert!(!config.compresses("application/octet-stream"));
+    }
+}
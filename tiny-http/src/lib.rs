@@ -103,8 +103,8 @@ use std::io::Error as IoError;
 use std::io::ErrorKind as IoErrorKind;
 use std::io::Result as IoResult;
 use std::net::{Shutdown, TcpStream, ToSocketAddrs};
-use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
 use std::sync::mpsc;
 use std::sync::Arc;
 use std::thread;
@@ -120,20 +120,26 @@ use connection::Connection;
 use util::MessagesQueue;
 
 pub use common::{HTTPVersion, Header, HeaderField, Method, StatusCode};
+pub use compression::{CompressionConfig, ContentEncoding};
 pub use connection::{ConfigListenAddr, ListenAddr, Listener};
+pub use multipart::{Multipart, MultipartPart};
 pub use request::{ReadWrite, Request};
 pub use response::{Response, ResponseBox};
 pub use test::TestRequest;
+pub use websocket::{WebSocketMessage, WebSocketReceiver, WebSocketSender};
 
 mod client;
 mod common;
+pub mod compression;
 mod connection;
 mod log;
+pub mod multipart;
 mod request;
 mod response;
 mod ssl;
 mod test;
 mod util;
+pub mod websocket;
 
 /// The main class of this library.
 ///
@@ -148,6 +154,10 @@ pub struct Server {
     // queue for messages received by child threads
     messages: Arc<MessagesQueue<Message>>,
 
+    // set by `drain()`: connections stop reading new requests and close after the
+    // response in progress
+    draining: Arc<AtomicBool>,
+
     // result of TcpListener::local_addr()
     listening_addr: ListenAddr,
 }
@@ -188,6 +198,66 @@ pub struct ServerConfig {
 
     /// If `Some`, then the server will use SSL to encode the communications.
     pub ssl: Option<SslConfig>,
+
+    /// Limits protecting the server from overload.
+    pub limits: ServerLimits,
+}
+
+/// Limits protecting the server from overload.
+///
+/// The default puts no limit on anything.
+#[derive(Debug, Clone, Default)]
+pub struct ServerLimits {
+    /// Maximum number of requests waiting to be taken with `recv()`. Requests arriving
+    /// while the queue is full are answered with `503 Service Unavailable`.
+    pub max_pending_requests: Option<usize>,
+
+    /// Sent as `Retry-After` (in whole seconds) in `503` responses to requests rejected
+    /// because the queue was full.
+    pub retry_after: Option<Duration>,
+
+    /// Longest time a client may take to send the head of a request (request line and
+    /// headers), and to start its first request after connecting. A head that is too
+    /// slow is answered with `408 Request Timeout`.
+    pub header_read_timeout: Option<Duration>,
+
+    /// Longest wait for more data of a request body. Reading the body then fails with an
+    /// error of kind `WouldBlock` or `TimedOut`.
+    pub body_read_timeout: Option<Duration>,
+
+    /// Longest time a keep-alive connection stays open without a new request, counted
+    /// from the previous response.
+    pub keep_alive_timeout: Option<Duration>,
+
+    /// Longest wait for the client to take more of a response.
+    pub write_timeout: Option<Duration>,
+
+    /// Number of requests after which a keep-alive connection is closed. The response to
+    /// the last one carries `Connection: close`.
+    pub max_requests_per_connection: Option<usize>,
+
+    /// Maximum number of open connections. Connections beyond it are closed right away.
+    /// Each open connection has a thread of its own reading its requests, so this also
+    /// bounds the number of those threads; `max_pending_requests` doesn't.
+    pub max_connections: Option<usize>,
+
+    /// Maximum length of a request's URL. Longer ones are answered with
+    /// `414 URI Too Long`.
+    pub max_url_length: Option<usize>,
+
+    /// Maximum size of a request's head, the request line and headers with their line
+    /// endings. Larger heads are answered with `431 Request Header Fields Too Large`
+    /// (`414 URI Too Long` if the request line alone is too long).
+    pub max_header_bytes: Option<usize>,
+
+    /// Maximum number of headers in a request. More are answered with
+    /// `431 Request Header Fields Too Large`.
+    pub max_header_count: Option<usize>,
+
+    /// Maximum size of a request body. A larger `Content-Length` is answered with
+    /// `413 Payload Too Large`; reading a larger chunked body fails once past the limit,
+    /// which `Request::body_too_large()` then tells.
+    pub max_body_size: Option<usize>,
 }
 
 /// Configuration of the server for SSL.
@@ -209,6 +279,7 @@ impl Server {
         Server::new(ServerConfig {
             addr: ConfigListenAddr::from_socket_addrs(addr)?,
             ssl: None,
+            limits: ServerLimits::default(),
         })
     }
 
@@ -229,6 +300,7 @@ impl Server {
         Server::new(ServerConfig {
             addr: ConfigListenAddr::from_socket_addrs(addr)?,
             ssl: Some(config),
+            limits: ServerLimits::default(),
         })
     }
 
@@ -240,13 +312,14 @@ impl Server {
         Server::new(ServerConfig {
             addr: ConfigListenAddr::unix_from_path(path),
             ssl: None,
+            limits: ServerLimits::default(),
         })
     }
 
     /// Builds a new server that listens on the specified address.
     pub fn new(config: ServerConfig) -> Result<Server, Box<dyn Error + Send + Sync + 'static>> {
         let listener = config.addr.bind()?;
-        Self::from_listener(listener, config.ssl)
+        Self::from_listener_with_limits(listener, config.ssl, config.limits)
     }
 
     /// Builds a new server using the specified TCP listener.
@@ -256,10 +329,20 @@ impl Server {
     pub fn from_listener<L: Into<Listener>>(
         listener: L,
         ssl_config: Option<SslConfig>,
+    ) -> Result<Server, Box<dyn Error + Send + Sync + 'static>> {
+        Self::from_listener_with_limits(listener, ssl_config, ServerLimits::default())
+    }
+
+    /// Same as `from_listener()`, with limits protecting the server from overload.
+    pub fn from_listener_with_limits<L: Into<Listener>>(
+        listener: L,
+        ssl_config: Option<SslConfig>,
+        limits: ServerLimits,
     ) -> Result<Server, Box<dyn Error + Send + Sync + 'static>> {
         let listener = listener.into();
         // building the "close" variable
         let close_trigger = Arc::new(AtomicBool::new(false));
+        let draining = Arc::new(AtomicBool::new(false));
 
         // building the TcpListener
         let (server, local_addr) = {
@@ -315,18 +398,35 @@ impl Server {
 
         // creating a task where server.accept() is continuously called
         // and ClientConnection objects are pushed in the messages queue
-        let messages = MessagesQueue::with_capacity(8);
+        let messages = MessagesQueue::with_limit(8, limits.max_pending_requests);
 
         let inside_close_trigger = close_trigger.clone();
+        let inside_draining = draining.clone();
         let inside_messages = messages.clone();
         thread::spawn(move || {
             // a tasks pool is used to dispatch the connections into threads
             let tasks_pool = util::TaskPool::new();
+            let open_connections = Arc::new(AtomicUsize::new(0));
 
             log::debug!("Running accept thread");
             while !inside_close_trigger.load(Relaxed) {
                 let new_client = match server.accept() {
                     Ok((sock, _)) => {
+                        if limits
+                            .max_connections
+                            .map_or(false, |max| open_connections.load(Relaxed) >= max)
+                        {
+                            log::debug!("Too many open connections, closing new connection");
+                            continue;
+                        }
+
+                        // the timeouts also apply to the SSL handshake
+                        let socket = sock.try_clone().ok();
+                        if let Some(socket) = &socket {
+                            socket.set_read_timeout(limits.header_read_timeout).ok();
+                            socket.set_write_timeout(limits.write_timeout).ok();
+                        }
+
                         use util::RefinedTcpStream;
                         let (read_closable, write_closable) = match ssl {
                             None => RefinedTcpStream::new(sock),
@@ -353,7 +453,13 @@ impl Server {
                             Some(ref _ssl) => unreachable!(),
                         };
 
-                        Ok(ClientConnection::new(write_closable, read_closable))
+                        Ok(ClientConnection::new(
+                            write_closable,
+                            read_closable,
+                            socket,
+                            inside_draining.clone(),
+                            limits.clone(),
+                        ))
                     }
                     Err(e) => Err(e),
                 };
@@ -361,19 +467,26 @@ impl Server {
                 match new_client {
                     Ok(client) => {
                         let messages = inside_messages.clone();
+                        let retry_after = limits.retry_after;
+                        let mut open_connection = Some(OpenConnection::new(&open_connections));
                         let mut client = Some(client);
                         tasks_pool.spawn(Box::new(move || {
+                            let _open_connection = open_connection.take();
                             if let Some(client) = client.take() {
                                 // Synchronization is needed for HTTPS requests to avoid a deadlock
                                 if client.secure() {
                                     let (sender, receiver) = mpsc::channel();
                                     for rq in client {
-                                        messages.push(rq.with_notify_sender(sender.clone()).into());
+                                        push_request(
+                                            &messages,
+                                            rq.with_notify_sender(sender.clone()),
+                                            retry_after,
+                                        );
                                         receiver.recv().unwrap();
                                     }
                                 } else {
                                     for rq in client {
-                                        messages.push(rq.into());
+                                        push_request(&messages, rq, retry_after);
                                     }
                                 }
                             }
@@ -393,6 +506,7 @@ impl Server {
         // result
         Ok(Server {
             messages,
+            draining,
             close: close_trigger,
             listening_addr: local_addr,
         })
@@ -451,17 +565,25 @@ impl Server {
     pub fn unblock(&self) {
         self.messages.unblock();
     }
-}
 
-impl Iterator for IncomingRequests<'_> {
-    type Item = Request;
-    fn next(&mut self) -> Option<Request> {
-        self.server.recv().ok()
+    /// Stops accepting connections, so that the server can shut down gracefully.
+    ///
+    /// The listening socket is closed. Requests already received can still be taken with
+    /// `recv()` and friends and answered, and those responses carry a
+    /// `Connection: close` header. No further request is read from open connections; one
+    /// that arrives anyway is answered with `503 Service Unavailable`.
+    pub fn drain(&self) {
+        self.draining.store(true, Relaxed);
+        self.stop_accepting();
     }
-}
 
-impl Drop for Server {
-    fn drop(&mut self) {
+    /// Returns true once `drain()` has been called.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Relaxed)
+    }
+
+    /// Stops the accept thread, which closes the listening socket.
+    fn stop_accepting(&self) {
         self.close.store(true, Relaxed);
         // Connect briefly to ourselves to unblock the accept thread
         let maybe_stream = match &self.listening_addr {
@@ -475,6 +597,53 @@ impl Drop for Server {
         if let Ok(stream) = maybe_stream {
             let _ = stream.shutdown(Shutdown::Both);
         }
+    }
+}
+
+/// Counts a connection as open until dropped.
+struct OpenConnection(Arc<AtomicUsize>);
+
+impl OpenConnection {
+    fn new(count: &Arc<AtomicUsize>) -> OpenConnection {
+        count.fetch_add(1, Relaxed);
+        OpenConnection(count.clone())
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Relaxed);
+    }
+}
+
+/// Queues a request for `recv()`, or answers it with `503 Service Unavailable` if too
+/// many requests are already waiting.
+fn push_request(messages: &MessagesQueue<Message>, rq: Request, retry_after: Option<Duration>) {
+    if let Err(Message::NewRequest(rq)) = messages.try_push(rq.into()) {
+        log::debug!("Request queue full, rejecting request");
+        let mut response =
+            Response::from_string("Server is busy").with_status_code(StatusCode(503));
+        if let Some(retry_after) = retry_after {
+            response.add_header(
+                Header::from_bytes(&b"Retry-After"[..], retry_after.as_secs().to_string()).unwrap(),
+            );
+        }
+        rq.respond(response).ok();
+    }
+}
+
+impl Iterator for IncomingRequests<'_> {
+    type Item = Request;
+    fn next(&mut self) -> Option<Request> {
+        self.server.recv().ok()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        if !self.close.load(Relaxed) {
+            self.stop_accepting();
+        }
 
         if let ListenAddr::Unix(addr) = &self.listening_addr {
             if let Some(path) = addr.as_pathname() {
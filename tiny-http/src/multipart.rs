@@ -0,0 +1,484 @@
+//! Streaming parser for `multipart/form-data` bodies (RFC 7578).
+//!
+//! Parts are read one after the other from the underlying reader, and the content of
+//! each part is exposed through `Read`, so large uploads never have to be held in memory.
+
+use std::io::Error as IoError;
+use std::io::Result as IoResult;
+use std::io::{ErrorKind, Read};
+
+/// Maximum size of the headers of a single part.
+const MAX_PART_HEADERS_SIZE: usize = 8 * 1024;
+
+/// Size of the chunks read from the underlying reader.
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+
+/// Headers and disposition of a single part.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultipartPart {
+    /// The headers of the part, in order. Values are decoded as UTF-8 (lossily), since
+    /// browsers send non-ASCII filenames as raw UTF-8.
+    pub headers: Vec<(String, String)>,
+    /// The `name` parameter of `Content-Disposition`.
+    pub name: Option<String>,
+    /// The `filename` parameter of `Content-Disposition`, or its RFC 5987 `filename*`
+    /// form when it is given.
+    pub filename: Option<String>,
+}
+
+impl MultipartPart {
+    /// Returns the value of the first header with this name (case-insensitive).
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Returns the `Content-Type` of the part.
+    pub fn content_type(&self) -> Option<&str> {
+        self.header("Content-Type")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Reading the data of the current part (or the preamble before the first one).
+    Body,
+    /// A delimiter was just consumed; the next part's headers come next.
+    Delimiter,
+    /// The closing delimiter was reached.
+    Done,
+}
+
+/// Reads the parts of a `multipart/form-data` body.
+///
+/// Call `next_part` to move to the next part, then read its content through the `Read`
+/// implementation. Any unread content of the current part is skipped by `next_part`.
+pub struct Multipart<R> {
+    reader: R,
+    /// `\r\n--boundary`
+    delimiter: Vec<u8>,
+    buffer: Vec<u8>,
+    position: usize,
+    eof: bool,
+    state: State,
+}
+
+impl<R: Read> Multipart<R> {
+    /// Builds a parser for a body delimited by `boundary`.
+    pub fn new(reader: R, boundary: &str) -> Multipart<R> {
+        let mut delimiter = b"\r\n--".to_vec();
+        delimiter.extend_from_slice(boundary.as_bytes());
+
+        Multipart {
+            reader,
+            delimiter,
+            // the first delimiter isn't preceded by a line break, pretend that it is
+            buffer: b"\r\n".to_vec(),
+            position: 0,
+            eof: false,
+            state: State::Body,
+        }
+    }
+
+    /// Builds a parser from the value of the request's `Content-Type` header.
+    ///
+    /// Returns `None` if this isn't a `multipart/*` content type with a boundary.
+    pub fn from_content_type(reader: R, content_type: &str) -> Option<Multipart<R>> {
+        let mut params = content_type.split(';');
+        let media_type = params.next()?.trim();
+        if !media_type
+            .get(..10)
+            .map_or(false, |t| t.eq_ignore_ascii_case("multipart/"))
+        {
+            return None;
+        }
+
+        let boundary = params.find_map(|param| {
+            let (name, value) = param.split_once('=')?;
+            if name.trim().eq_ignore_ascii_case("boundary") {
+                Some(unquote(value.trim()))
+            } else {
+                None
+            }
+        })?;
+        if boundary.is_empty() || boundary.len() > 70 {
+            return None;
+        }
+
+        Some(Multipart::new(reader, &boundary))
+    }
+
+    /// Skips to the next part and returns its headers, or `None` after the last part.
+    pub fn next_part(&mut self) -> IoResult<Option<MultipartPart>> {
+        if self.state == State::Body {
+            let mut sink = [0; READ_CHUNK_SIZE];
+            while self.read(&mut sink)? != 0 {}
+        }
+        if self.state == State::Done {
+            return Ok(None);
+        }
+
+        // `--` right after the delimiter marks the end of the body
+        self.fill(2)?;
+        if self.available().starts_with(b"--") {
+            self.state = State::Done;
+            return Ok(None);
+        }
+
+        // skip transport padding up to the end of the delimiter line
+        self.read_line()?;
+
+        let mut headers = Vec::new();
+        let mut size = 0;
+        loop {
+            let line = self.read_line()?;
+            if line.is_empty() {
+                break;
+            }
+            size += line.len();
+            if size > MAX_PART_HEADERS_SIZE {
+                return Err(IoError::new(
+                    ErrorKind::InvalidData,
+                    "multipart part headers are too large",
+                ));
+            }
+
+            let line = String::from_utf8_lossy(&line);
+            let (name, value) = line.split_once(':').ok_or_else(|| {
+                IoError::new(ErrorKind::InvalidData, "invalid multipart part header")
+            })?;
+            headers.push((name.trim().to_owned(), value.trim().to_owned()));
+        }
+
+        let mut part = MultipartPart {
+            headers,
+            name: None,
+            filename: None,
+        };
+        if let Some(disposition) = part.header("Content-Disposition") {
+            let (name, filename) = parse_disposition(disposition);
+            part.name = name;
+            part.filename = filename;
+        }
+
+        self.state = State::Body;
+        Ok(Some(part))
+    }
+
+    fn available(&self) -> &[u8] {
+        &self.buffer[self.position..]
+    }
+
+    /// Reads from the underlying reader until at least `len` bytes are buffered or the
+    /// end of the body is reached.
+    fn fill(&mut self, len: usize) -> IoResult<()> {
+        while self.available().len() < len && !self.eof {
+            if self.position > 0 {
+                self.buffer.drain(..self.position);
+                self.position = 0;
+            }
+
+            let start = self.buffer.len();
+            self.buffer.resize(start + READ_CHUNK_SIZE, 0);
+            let read = match self.reader.read(&mut self.buffer[start..]) {
+                Ok(read) => read,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {
+                    self.buffer.truncate(start);
+                    continue;
+                }
+                Err(e) => {
+                    self.buffer.truncate(start);
+                    return Err(e);
+                }
+            };
+            self.buffer.truncate(start + read);
+            if read == 0 {
+                self.eof = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a line terminated by `\r\n`, without the terminator.
+    fn read_line(&mut self) -> IoResult<Vec<u8>> {
+        loop {
+            if let Some(end) = find(self.available(), b"\r\n") {
+                let line = self.available()[..end].to_vec();
+                self.position += end + 2;
+                return Ok(line);
+            }
+            if self.eof {
+                return Err(unexpected_eof());
+            }
+            if self.available().len() > MAX_PART_HEADERS_SIZE {
+                return Err(IoError::new(
+                    ErrorKind::InvalidData,
+                    "multipart part headers are too large",
+                ));
+            }
+            let len = self.available().len() + 1;
+            self.fill(len)?;
+        }
+    }
+}
+
+impl<R: Read> Read for Multipart<R> {
+    /// Reads the content of the current part. Returns `Ok(0)` at the end of the part.
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        if self.state != State::Body || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let delimiter_len = self.delimiter.len();
+        self.fill(delimiter_len)?;
+
+        let available = &self.buffer[self.position..];
+        let len = match find(available, &self.delimiter) {
+            Some(0) => {
+                self.position += delimiter_len;
+                self.state = State::Delimiter;
+                return Ok(0);
+            }
+            Some(index) => index,
+            None if self.eof => return Err(unexpected_eof()),
+            // the end of the buffer might be the start of a delimiter
+            None => available.len() + 1 - delimiter_len,
+        };
+
+        let len = len.min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+fn unexpected_eof() -> IoError {
+    IoError::new(
+        ErrorKind::UnexpectedEof,
+        "multipart body ended before the closing boundary",
+    )
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Extracts the `name` and `filename` parameters of a `Content-Disposition` value.
+///
+/// An RFC 5987 `filename*` parameter (`filename*=UTF-8''na%C3%AFve.txt`) takes precedence
+/// over `filename`, when its charset is UTF-8 or ISO-8859-1.
+fn parse_disposition(disposition: &str) -> (Option<String>, Option<String>) {
+    let mut name = None;
+    let mut filename = None;
+    let mut ext_filename = None;
+
+    let mut rest = disposition;
+    while let Some(index) = rest.find(';') {
+        rest = &rest[index + 1..];
+        let (key, value) = match rest.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim_start()),
+            None => break,
+        };
+
+        // values may be quoted strings containing `;`
+        let (value, remaining) = if value.starts_with('"') {
+            let end = quoted_end(value);
+            (unquote(&value[..end]), &value[end..])
+        } else {
+            let end = value.find(';').unwrap_or(value.len());
+            (value[..end].trim().to_owned(), &value[end..])
+        };
+        rest = remaining;
+
+        if key.eq_ignore_ascii_case("name") {
+            name = Some(value);
+        } else if key.eq_ignore_ascii_case("filename") {
+            filename = Some(value);
+        } else if key.eq_ignore_ascii_case("filename*") {
+            ext_filename = decode_ext_value(&value);
+        }
+    }
+
+    (name, ext_filename.or(filename))
+}
+
+/// Decodes an RFC 5987 extended value, `charset'language'percent-encoded-value`.
+///
+/// Returns `None` for charsets other than UTF-8 and ISO-8859-1, and for malformed values.
+fn decode_ext_value(value: &str) -> Option<String> {
+    let mut fields = value.splitn(3, '\'');
+    let charset = fields.next()?;
+    let _language = fields.next()?;
+    let encoded = fields.next()?.as_bytes();
+
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut index = 0;
+    while index < encoded.len() {
+        if encoded[index] == b'%' {
+            let hex = std::str::from_utf8(encoded.get(index + 1..index + 3)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            bytes.push(encoded[index]);
+            index += 1;
+        }
+    }
+
+    if charset.eq_ignore_ascii_case("UTF-8") {
+        String::from_utf8(bytes).ok()
+    } else if charset.eq_ignore_ascii_case("ISO-8859-1") {
+        Some(bytes.into_iter().map(char::from).collect())
+    } else {
+        None
+    }
+}
+
+/// Returns the index just after the closing quote of a quoted string.
+fn quoted_end(value: &str) -> usize {
+    let mut escaped = false;
+    for (index, c) in value.char_indices().skip(1) {
+        match c {
+            '\\' if !escaped => escaped = true,
+            '"' if !escaped => return index + 1,
+            _ => escaped = false,
+        }
+    }
+    value.len()
+}
+
+/// Removes the quotes and escapes of a quoted string. Other values are returned as-is.
+fn unquote(value: &str) -> String {
+    let inner = match value.strip_prefix('"') {
+        Some(inner) => inner,
+        None => return value.to_owned(),
+    };
+    let inner = inner.strip_suffix('"').unwrap_or(inner);
+
+    let mut unquoted = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unquoted.extend(chars.next()),
+            c => unquoted.push(c),
+        }
+    }
+    unquoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_disposition, Multipart};
+    use std::io::{Cursor, Read};
+
+    const BODY: &[u8] = b"preamble\r\n--XyZ\r\n\
+        Content-Disposition: form-data; name=\"field\"\r\n\r\n\
+        value\r\n--XyZ\r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"a;b.txt\"\r\n\
+        Content-Type: text/plain\r\n\r\n\
+        line one\r\n-XyZ\r\n--Xy\r\n\r\n--XyZ--\r\nepilogue";
+
+    /// Reads one byte at a time, to exercise delimiters split across reads.
+    struct Trickle<R>(R);
+
+    impl<R: Read> Read for Trickle<R> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(1);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    fn collect<R: Read>(mut multipart: Multipart<R>) -> Vec<(Option<String>, String)> {
+        let mut parts = Vec::new();
+        while let Some(part) = multipart.next_part().unwrap() {
+            let mut content = String::new();
+            multipart.read_to_string(&mut content).unwrap();
+            parts.push((part.filename, content));
+        }
+        parts
+    }
+
+    #[test]
+    fn parts() {
+        let multipart =
+            Multipart::from_content_type(Cursor::new(BODY), "multipart/form-data; boundary=XyZ")
+                .unwrap();
+        let expected = vec![
+            (None, "value".to_owned()),
+            (
+                Some("a;b.txt".to_owned()),
+                "line one\r\n-XyZ\r\n--Xy\r\n".to_owned(),
+            ),
+        ];
+        assert_eq!(collect(multipart), expected);
+
+        let multipart = Multipart::new(Trickle(Cursor::new(BODY)), "XyZ");
+        assert_eq!(collect(multipart), expected);
+    }
+
+    #[test]
+    fn skips_unread_content() {
+        let mut multipart = Multipart::new(Cursor::new(BODY), "XyZ");
+        assert_eq!(
+            multipart.next_part().unwrap().unwrap().name.as_deref(),
+            Some("field")
+        );
+        let part = multipart.next_part().unwrap().unwrap();
+        assert_eq!(part.content_type(), Some("text/plain"));
+        assert!(multipart.next_part().unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_body() {
+        let mut multipart = Multipart::new(Cursor::new(&BODY[..BODY.len() - 30]), "XyZ");
+        multipart.next_part().unwrap().unwrap();
+        multipart.next_part().unwrap().unwrap();
+        let mut content = Vec::new();
+        assert!(multipart.read_to_end(&mut content).is_err());
+    }
+
+    #[test]
+    fn content_type_without_boundary() {
+        assert!(Multipart::from_content_type(Cursor::new(BODY), "multipart/form-data").is_none());
+        assert!(Multipart::from_content_type(Cursor::new(BODY), "text/plain").is_none());
+        assert!(Multipart::from_content_type(
+            Cursor::new(BODY),
+            "multipart/form-data; boundary=\"XyZ\""
+        )
+        .is_some());
+    }
+
+    #[test]
+    fn disposition() {
+        assert_eq!(
+            parse_disposition(r#"form-data; name="a\"b"; filename=x.txt"#),
+            (Some("a\"b".to_owned()), Some("x.txt".to_owned()))
+        );
+    }
+
+    #[test]
+    fn extended_filename() {
+        assert_eq!(
+            parse_disposition(
+                "form-data; name=f; filename=\"naive.txt\"; filename*=UTF-8''na%C3%AFve.txt"
+            ),
+            (Some("f".to_owned()), Some("na\u{ef}ve.txt".to_owned()))
+        );
+        assert_eq!(
+            parse_disposition("form-data; name=f; filename*=iso-8859-1'en'na%EFve.txt"),
+            (Some("f".to_owned()), Some("na\u{ef}ve.txt".to_owned()))
+        );
+        // unknown charsets and malformed values fall back to `filename`
+        assert_eq!(
+            parse_disposition("form-data; filename=a.txt; filename*=UTF-8''%C3"),
+            (None, Some("a.txt".to_owned()))
+        );
+        assert_eq!(
+            parse_disposition("form-data; filename=a.txt; filename*=KOI8-R''%C1"),
+            (None, Some("a.txt".to_owned()))
+        );
+    }
+}
@@ -5,9 +5,11 @@ use std::fmt;
 use std::net::SocketAddr;
 use std::str::FromStr;
 
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
 
-use crate::util::{EqualReader, FusedReader};
+use crate::util::{EqualReader, FusedReader, LimitedReader};
 use crate::{HTTPVersion, Header, Method, Response, StatusCode};
 use chunked_transfer::Decoder;
 
@@ -74,6 +76,18 @@ pub struct Request {
 
     // If Some, a message must be sent after responding
     notify_when_responded: Option<Sender<()>>,
+
+    // set when the server is draining, the response then closes the connection
+    draining: Option<Arc<AtomicBool>>,
+
+    // true if this is the last request read from the connection
+    close_connection: bool,
+
+    // set when reading the body failed because it is larger than the limit
+    body_too_large: Arc<AtomicBool>,
+
+    // set when reading the body failed because the client was too slow to send it
+    body_timed_out: Arc<AtomicBool>,
 }
 
 struct NotifyOnDrop<R> {
@@ -100,6 +114,58 @@ impl<R> Drop for NotifyOnDrop<R> {
     }
 }
 
+/// Response writer shared between a `Request` and a body reader obtained with
+/// `Request::take_reader`, so that the reader can still send the `100 Continue`.
+struct SharedWriter {
+    inner: Arc<Mutex<SharedWriterState>>,
+}
+
+struct SharedWriterState {
+    writer: Box<dyn Write + Send + 'static>,
+    // true as soon as the final response has started being written
+    response_started: bool,
+}
+
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.inner.lock().unwrap();
+        state.response_started = true;
+        state.writer.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.lock().unwrap().writer.flush()
+    }
+}
+
+/// Body reader that sends a `100 Continue` the first time it is read from, unless the
+/// final response has already started.
+struct ContinueReader<R> {
+    inner: R,
+    http_version: HTTPVersion,
+    writer: Option<Arc<Mutex<SharedWriterState>>>,
+}
+
+impl<R: Read> Read for ContinueReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(shared) = self.writer.take() {
+            let mut state = shared.lock().unwrap();
+            if !state.response_started {
+                let msg = Response::empty(StatusCode(100));
+                msg.raw_print(
+                    state.writer.by_ref(),
+                    self.http_version.clone(),
+                    &[],
+                    true,
+                    None,
+                )?;
+                state.writer.flush()?;
+            }
+        }
+
+        self.inner.read(buf)
+    }
+}
+
 /// Error that can happen when building a `Request` object.
 #[derive(Debug)]
 pub enum RequestCreationError {
@@ -108,6 +174,9 @@ pub enum RequestCreationError {
 
     /// Error while reading data from the socket during the creation of the `Request`.
     CreationIoError(IoError),
+
+    /// The `Content-Length` is above the body size limit.
+    PayloadTooLarge,
 }
 
 impl From<IoError> for RequestCreationError {
@@ -125,6 +194,9 @@ impl From<IoError> for RequestCreationError {
 /// It is the responsibility of the `Request` to read only the data of the request and not further.
 ///
 /// The `Write` object will be used by the `Request` to write the response.
+///
+/// With `max_body_size`, a larger `Content-Length` is an error, and reading a larger
+/// chunked body fails once past the limit.
 #[allow(clippy::too_many_arguments)]
 pub fn new_request<R, W>(
     secure: bool,
@@ -135,6 +207,7 @@ pub fn new_request<R, W>(
     remote_addr: Option<SocketAddr>,
     mut source_data: R,
     writer: W,
+    max_body_size: Option<usize>,
 ) -> Result<Request, RequestCreationError>
 where
     R: Read + Send + 'static,
@@ -158,6 +231,13 @@ where
             .and_then(|h| FromStr::from_str(h.value.as_str()).ok())
     };
 
+    if let (Some(content_length), Some(max_body_size)) = (content_length, max_body_size) {
+        if content_length > max_body_size {
+            return Err(RequestCreationError::PayloadTooLarge);
+        }
+    }
+    let body_too_large = Arc::new(AtomicBool::new(false));
+
     // true if the client sent a `Expect: 100-continue` header
     let expects_continue = {
         match headers
@@ -218,7 +298,15 @@ where
     } else if transfer_encoding.is_some() {
         // if a transfer-encoding was specified, then "chunked" is ALWAYS applied
         // over the message (RFC2616 #3.6)
-        Box::new(FusedReader::new(Decoder::new(source_data))) as Box<dyn Read + Send + 'static>
+        let decoder = Decoder::new(source_data);
+        match max_body_size {
+            Some(limit) => Box::new(FusedReader::new(LimitedReader::new(
+                decoder,
+                limit,
+                body_too_large.clone(),
+            ))) as Box<dyn Read + Send + 'static>,
+            None => Box::new(FusedReader::new(decoder)) as Box<dyn Read + Send + 'static>,
+        }
     } else {
         // if we have neither a Content-Length nor a Transfer-Encoding,
         // assuming that we have no data
@@ -238,6 +326,10 @@ where
         body_length: content_length,
         must_send_continue: expects_continue,
         notify_when_responded: None,
+        draining: None,
+        close_connection: false,
+        body_too_large,
+        body_timed_out: Arc::new(AtomicBool::new(false)),
     })
 }
 
@@ -280,6 +372,27 @@ impl Request {
         self.body_length
     }
 
+    /// Returns true if reading the body failed because it is larger than the server's
+    /// body size limit.
+    ///
+    /// Bodies with a `Content-Length` above the limit are answered with
+    /// `413 Payload Too Large` before reaching `recv()`, so this only happens to chunked
+    /// bodies.
+    #[inline]
+    pub fn body_too_large(&self) -> bool {
+        self.body_too_large.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if reading the body failed because the client didn't send any of it
+    /// within the server's body read timeout.
+    ///
+    /// Small bodies are read before reaching `recv()`, and a timeout there is answered
+    /// with `408 Request Timeout` right away.
+    #[inline]
+    pub fn body_timed_out(&self) -> bool {
+        self.body_timed_out.load(Ordering::Relaxed)
+    }
+
     /// Returns the address of the client that sent this request.
     ///
     /// The address is always `Some` for TCP listeners, but always `None` for UNIX listeners
@@ -303,12 +416,25 @@ impl Request {
     ///  is destroyed before continuing to read or write on the socket. Therefore you should always
     ///  destroy it as soon as possible.
     pub fn upgrade<R: Read>(
-        mut self,
+        self,
         protocol: &str,
         response: Response<R>,
     ) -> Box<dyn ReadWrite + Send> {
         use crate::util::CustomStream;
 
+        let (reader, writer) = self.upgrade_split(protocol, response);
+        Box::new(CustomStream::new(reader, writer)) as Box<dyn ReadWrite + Send>
+    }
+
+    /// Same as `upgrade`, but returns the reading and writing halves of the stream separately.
+    ///
+    /// This allows reading from and writing to the upgraded connection from different threads,
+    ///  which most protocols built on top of an upgrade (such as websockets) need.
+    pub fn upgrade_split<R: Read>(
+        mut self,
+        protocol: &str,
+        response: Response<R>,
+    ) -> (Box<dyn Read + Send>, Box<dyn Write + Send>) {
         response
             .raw_print(
                 self.response_writer.as_mut().unwrap().by_ref(),
@@ -321,15 +447,16 @@ impl Request {
 
         self.response_writer.as_mut().unwrap().flush().ok(); // TODO: unused result
 
-        let stream = CustomStream::new(self.extract_reader_impl(), self.extract_writer_impl());
+        let reader = self.extract_reader_impl();
+        let writer = self.extract_writer_impl();
         if let Some(sender) = self.notify_when_responded.take() {
-            let stream = NotifyOnDrop {
+            let writer = NotifyOnDrop {
                 sender,
-                inner: stream,
+                inner: writer,
             };
-            Box::new(stream) as Box<dyn ReadWrite + Send>
+            (reader, Box::new(writer) as Box<dyn Write + Send>)
         } else {
-            Box::new(stream) as Box<dyn ReadWrite + Send>
+            (reader, writer)
         }
     }
 
@@ -376,6 +503,40 @@ impl Request {
         self.data_reader.as_mut().unwrap()
     }
 
+    /// Takes the body of the request out of the `Request`, leaving an empty body behind.
+    ///
+    /// Unlike `as_reader`, the returned reader is owned and can be consumed on another thread
+    /// while the `Request` is kept around to send the response.
+    ///
+    /// If the client sent a `Expect: 100-continue` header with the request, the `100 Continue`
+    /// response is only sent when the returned reader is first read from, and only if
+    /// `respond` hasn't started writing the final response by then.
+    pub fn take_reader(&mut self) -> Box<dyn Read + Send + 'static> {
+        let reader = self
+            .data_reader
+            .replace(Box::new(io::empty()))
+            .unwrap_or_else(|| Box::new(io::empty()));
+
+        if !self.must_send_continue || self.response_writer.is_none() {
+            return reader;
+        }
+        self.must_send_continue = false;
+
+        let shared = Arc::new(Mutex::new(SharedWriterState {
+            writer: self.extract_writer_impl(),
+            response_started: false,
+        }));
+        self.response_writer = Some(Box::new(SharedWriter {
+            inner: shared.clone(),
+        }));
+
+        Box::new(ContinueReader {
+            inner: reader,
+            http_version: self.http_version.clone(),
+            writer: Some(shared),
+        })
+    }
+
     /// Turns the `Request` into a writer.
     ///
     /// The writer has a raw access to the stream to the user.
@@ -440,12 +601,21 @@ impl Request {
         res
     }
 
-    fn respond_impl<R>(&mut self, response: Response<R>) -> Result<(), IoError>
+    fn respond_impl<R>(&mut self, mut response: Response<R>) -> Result<(), IoError>
     where
         R: Read,
     {
         let mut writer = self.extract_writer_impl();
 
+        // a draining server closes keep-alive connections after the current response
+        let draining = self
+            .draining
+            .as_ref()
+            .map_or(false, |draining| draining.load(Ordering::Relaxed));
+        if draining || self.close_connection {
+            response.close_connection();
+        }
+
         let do_not_send_body = self.method == Method::Head;
 
         Self::ignore_client_closing_errors(response.raw_print(
@@ -473,6 +643,25 @@ impl Request {
         self.notify_when_responded = Some(sender);
         self
     }
+
+    pub(crate) fn with_draining(mut self, draining: Arc<AtomicBool>) -> Self {
+        self.draining = Some(draining);
+        self
+    }
+
+    /// Uses `timed_out`, set by the connection when a body read times out, for
+    /// `body_timed_out()`.
+    pub(crate) fn with_body_timed_out(mut self, timed_out: Arc<AtomicBool>) -> Self {
+        self.body_timed_out = timed_out;
+        self
+    }
+
+    /// Marks the request as the last one of its connection, so that the response
+    /// closes it.
+    pub(crate) fn with_connection_close(mut self) -> Self {
+        self.close_connection = true;
+        self
+    }
 }
 
 impl fmt::Debug for Request {
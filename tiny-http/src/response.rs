@@ -1,10 +1,14 @@
-use crate::common::{HTTPVersion, Header, StatusCode};
+use crate::common::{HTTPVersion, Header, Method, StatusCode};
+use crate::compression::{CompressReader, CompressionConfig, ContentEncoding};
+use crate::util::{parse_range_header, ByteRangesReader};
+use crate::Request;
 use httpdate::HttpDate;
 use std::cmp::Ordering;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
 use std::sync::mpsc::Receiver;
 
 use std::io::Result as IoResult;
-use std::io::{self, Cursor, Read, Write};
+use std::io::{self, Cursor, Read, Seek, Write};
 
 use std::fs::File;
 
@@ -25,7 +29,7 @@ use std::time::SystemTime;
 ///
 ///  - `Content-Encoding`: If you define this header, the library
 ///     will assume that the data from the `Read` object has the specified encoding
-///     and will just pass-through.
+///     and will just pass-through. Use `with_compression` to have the body compressed.
 ///
 ///  - `Content-Length`: The length of the data should be set manually
 ///     using the `Reponse` object's API. Attempting to set the value of this
@@ -43,6 +47,7 @@ pub struct Response<R> {
     headers: Vec<Header>,
     data_length: Option<usize>,
     chunked_threshold: Option<usize>,
+    streaming: bool,
 }
 
 /// A `Response` without a template parameter.
@@ -206,6 +211,7 @@ where
             headers: Vec::with_capacity(16),
             data_length,
             chunked_threshold: None,
+            streaming: false,
         };
 
         for h in headers {
@@ -231,6 +237,17 @@ where
         self
     }
 
+    /// Flush the data to the client as soon as it is read, instead of waiting for
+    /// the chunk buffer to fill up.
+    ///
+    /// This only applies when the response is sent with chunked transfer encoding, and is
+    /// useful when the `Read` object produces data slowly (live logs, long polling, etc.).
+    /// The headers are also flushed right away, before the first read.
+    pub fn with_streaming(mut self, streaming: bool) -> Response<R> {
+        self.streaming = streaming;
+        self
+    }
+
     /// Convert the response into the underlying `Read` type.
     ///
     /// This is mainly useful for testing as it must consume the `Response`.
@@ -246,6 +263,12 @@ where
         self.chunked_threshold.unwrap_or(32768)
     }
 
+    /// Adds a `Connection: close` header, which `add_header` refuses.
+    pub(crate) fn close_connection(&mut self) {
+        self.headers
+            .push(Header::from_bytes(&b"Connection"[..], &b"close"[..]).unwrap());
+    }
+
     /// Adds a header to the list.
     /// Does all the checks.
     pub fn add_header<H>(&mut self, header: H)
@@ -319,6 +342,7 @@ where
             status_code: self.status_code,
             data_length,
             chunked_threshold: self.chunked_threshold,
+            streaming: self.streaming,
         }
     }
 
@@ -374,13 +398,20 @@ where
             transfer_encoding = None;
         }
 
+        // a response without a body (e.g. to a HEAD request) only describes it, so the
+        // length is sent whenever it is known rather than a chunked encoding
+        if do_not_send_body && self.data_length.is_some() && transfer_encoding.is_some() {
+            transfer_encoding = Some(TransferEncoding::Identity);
+        }
+
         // if the transfer encoding is identity, the content length must be known ; therefore if
         // we don't know it, we buffer the entire response first here
         // while this is an expensive operation, it is only ever needed for clients using HTTP 1.0
         let (mut reader, data_length): (Box<dyn Read>, _) =
             match (self.data_length, transfer_encoding) {
                 (Some(l), _) => (Box::new(self.reader), Some(l)),
-                (None, Some(TransferEncoding::Identity)) => {
+                // there is no point reading a body that won't be sent just to measure it
+                (None, Some(TransferEncoding::Identity)) if !do_not_send_body => {
                     let mut buf = Vec::new();
                     self.reader.read_to_end(&mut buf)?;
                     let l = buf.len();
@@ -404,16 +435,15 @@ where
                 .push(Header::from_bytes(&b"Transfer-Encoding"[..], &b"chunked"[..]).unwrap()),
 
             Some(TransferEncoding::Identity) => {
-                assert!(data_length.is_some());
-                let data_length = data_length.unwrap();
-
-                self.headers.push(
-                    Header::from_bytes(
-                        &b"Content-Length"[..],
-                        format!("{}", data_length).as_bytes(),
+                if let Some(data_length) = data_length {
+                    self.headers.push(
+                        Header::from_bytes(
+                            &b"Content-Length"[..],
+                            format!("{}", data_length).as_bytes(),
+                        )
+                        .unwrap(),
                     )
-                    .unwrap(),
-                )
+                }
             }
 
             _ => (),
@@ -430,6 +460,27 @@ where
         // sending the body
         if !do_not_send_body {
             match transfer_encoding {
+                Some(TransferEncoding::Chunked) if self.streaming => {
+                    use chunked_transfer::Encoder;
+
+                    // making sure the client gets the headers before the first chunk is ready
+                    writer.flush()?;
+
+                    // every read is sent as its own chunk and pushed to the socket
+                    let mut writer = Encoder::with_flush_after_write(writer);
+                    let mut buf = [0; 8192];
+                    loop {
+                        let read = match reader.read(&mut buf) {
+                            Ok(0) => break,
+                            Ok(read) => read,
+                            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                            Err(e) => return Err(e),
+                        };
+                        writer.write_all(&buf[..read])?;
+                        writer.get_mut().flush()?;
+                    }
+                }
+
                 Some(TransferEncoding::Chunked) => {
                     use chunked_transfer::Encoder;
 
@@ -481,6 +532,254 @@ where
             headers: self.headers,
             data_length: self.data_length,
             chunked_threshold: self.chunked_threshold,
+            streaming: self.streaming,
+        }
+    }
+}
+
+impl<R> Response<R>
+where
+    R: Read + Seek + Send + 'static,
+{
+    /// Answers the `Range` header of the request, if any, with only the requested bytes.
+    ///
+    /// Only `200` responses of known length to `GET` requests are affected; they get an
+    /// `Accept-Ranges: bytes` header. A single range is sent as `206 Partial Content`,
+    /// several ranges as a `multipart/byteranges` body, and unsatisfiable ranges give a
+    /// `416`. If the request has an `If-Range` header that doesn't match the `ETag` or
+    /// `Last-Modified` header of this response, the full body is sent instead.
+    ///
+    /// The reader is seeked to each range, so e.g. a file is never read in full.
+    pub fn with_ranges(mut self, request: &Request) -> ResponseBox {
+        let len = match self.data_length {
+            Some(len) if self.status_code.0 == 200 => len as u64,
+            _ => return self.boxed(),
+        };
+        self.add_header(Header::from_bytes(&b"Accept-Ranges"[..], &b"bytes"[..]).unwrap());
+
+        if *request.method() != Method::Get {
+            return self.boxed();
+        }
+        let range = match find_header(request.headers(), "Range") {
+            Some(range) => range,
+            None => return self.boxed(),
+        };
+        if let Some(if_range) = find_header(request.headers(), "If-Range") {
+            let validator = if if_range.starts_with('"') || if_range.starts_with("W/") {
+                find_header(&self.headers, "ETag").filter(|etag| !etag.starts_with("W/"))
+            } else {
+                find_header(&self.headers, "Last-Modified")
+            };
+            if validator != Some(if_range.trim()) {
+                return self.boxed();
+            }
+        }
+        let ranges = match parse_range_header(range, len) {
+            Some(ranges) => ranges,
+            None => return self.boxed(),
+        };
+
+        let content_type = find_header(&self.headers, "Content-Type").map(str::to_owned);
+        let mut headers = self.headers;
+        let reader = match ranges.len() {
+            0 => {
+                headers.retain(|h| !h.field.equiv("Content-Type"));
+                headers.push(content_range_header(&format!("bytes */{}", len)));
+                return Response::new(StatusCode(416), headers, io::empty(), Some(0), None).boxed();
+            }
+            1 => {
+                let range = ranges[0].clone();
+                headers.push(content_range_header(&format!(
+                    "bytes {}-{}/{}",
+                    range.start,
+                    range.end - 1,
+                    len
+                )));
+                ByteRangesReader::single(self.reader, range)
+            }
+            _ => {
+                let boundary = byteranges_boundary();
+                headers.retain(|h| !h.field.equiv("Content-Type"));
+                headers.push(
+                    Header::from_bytes(
+                        &b"Content-Type"[..],
+                        format!("multipart/byteranges; boundary={}", boundary).as_bytes(),
+                    )
+                    .unwrap(),
+                );
+                ByteRangesReader::multipart(
+                    self.reader,
+                    &ranges,
+                    &boundary,
+                    content_type.as_deref(),
+                    len,
+                )
+            }
+        };
+
+        let data_length = reader.len() as usize;
+        Response {
+            reader: Box::new(reader) as Box<dyn Read + Send>,
+            status_code: StatusCode(206),
+            headers,
+            data_length: Some(data_length),
+            chunked_threshold: self.chunked_threshold,
+            streaming: self.streaming,
+        }
+    }
+}
+
+impl<R> Response<R> {
+    /// Returns true if `with_compression` compresses this response's body for a client
+    /// that accepts it.
+    ///
+    /// Responses that already have a `Content-Encoding`, partial or bodiless responses,
+    /// bodies whose `Content-Type` isn't allowed by `config` and bodies smaller than
+    /// `config.min_size` are left alone.
+    pub fn is_compressible(&self, config: &CompressionConfig) -> bool {
+        match self.status_code.0 {
+            100..=199 | 204 | 206 | 304 => false,
+            _ => {
+                find_header(&self.headers, "Content-Encoding").is_none()
+                    && find_header(&self.headers, "Content-Type")
+                        .map_or(false, |t| config.compresses(t))
+                    && self.data_length.map_or(true, |len| len >= config.min_size)
+            }
+        }
+    }
+
+    /// Gives a `304 Not Modified` or `412 Precondition Failed` response the `Vary` and
+    /// `ETag` headers that `with_compression` gives the response it stands for, so that
+    /// caches see the same validators on both.
+    ///
+    /// `compressible` tells whether that response's body is compressible, as given by
+    /// `is_compressible`.
+    pub fn with_compression_validators(mut self, request: &Request, compressible: bool) -> Self {
+        if !compressible {
+            return self;
+        }
+        add_vary_accept_encoding(&mut self.headers);
+        if find_header(request.headers(), "Accept-Encoding")
+            .and_then(ContentEncoding::negotiate)
+            .is_some()
+        {
+            weaken_etags(&mut self.headers);
+        }
+        self
+    }
+}
+
+/// Adds `Vary: Accept-Encoding`, unless a `Vary` header already names it.
+fn add_vary_accept_encoding(headers: &mut Vec<Header>) {
+    let varies = headers
+        .iter()
+        .filter(|h| h.field.equiv("Vary"))
+        .flat_map(|h| h.value.as_str().split(','))
+        .any(|name| name.trim().eq_ignore_ascii_case("Accept-Encoding"));
+    if !varies {
+        headers.push(Header::from_bytes(&b"Vary"[..], &b"Accept-Encoding"[..]).unwrap());
+    }
+}
+
+/// Turns strong `ETag`s into weak ones.
+fn weaken_etags(headers: &mut [Header]) {
+    for header in headers.iter_mut().filter(|h| h.field.equiv("ETag")) {
+        if !header.value.as_str().starts_with("W/") {
+            let weak = format!("W/{}", header.value);
+            *header = Header::from_bytes(&b"ETag"[..], weak.as_bytes()).unwrap();
+        }
+    }
+}
+
+/// Returns the value of the first header with the given name.
+fn find_header<'a>(headers: &'a [Header], name: &'static str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| h.value.as_str())
+}
+
+fn content_range_header(value: &str) -> Header {
+    Header::from_bytes(&b"Content-Range"[..], value.as_bytes()).unwrap()
+}
+
+/// A boundary for `multipart/byteranges` bodies, unique enough not to appear in them.
+fn byteranges_boundary() -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!(
+        "tiny-http-{:x}-{:x}",
+        nanos,
+        COUNTER.fetch_add(1, AtomicOrdering::Relaxed)
+    )
+}
+
+impl<R> Response<R>
+where
+    R: Read + Send + 'static,
+{
+    /// Compresses the body with the best coding accepted by the request's
+    /// `Accept-Encoding` header.
+    ///
+    /// Only bodies whose `Content-Type` is allowed by `config` and that are not smaller
+    /// than `config.min_size` are compressed (see `is_compressible`); those responses
+    /// also get a `Vary: Accept-Encoding` header. A strong `ETag` of a successful response
+    /// becomes weak, since the compressed bytes differ from the original ones.
+    ///
+    /// Streaming responses stay streaming: each read is flushed through the encoder. If the
+    /// encoder can't be set up, the response is sent uncompressed.
+    pub fn with_compression(
+        mut self,
+        request: &Request,
+        config: &CompressionConfig,
+    ) -> ResponseBox {
+        if !self.is_compressible(config) {
+            return self.boxed();
+        }
+        add_vary_accept_encoding(&mut self.headers);
+
+        let encoding = match find_header(request.headers(), "Accept-Encoding")
+            .and_then(ContentEncoding::negotiate)
+        {
+            Some(encoding) => encoding,
+            None => return self.boxed(),
+        };
+        let mut reader = match CompressReader::new(self.reader, encoding, self.streaming) {
+            Ok(reader) => reader,
+            // better sent as-is than not at all
+            Err((reader, _)) => {
+                self.reader = reader;
+                return self.boxed();
+            }
+        };
+
+        // a HEAD response has no body, but still tells the length of the compressed one
+        let data_length = if *request.method() == Method::Head && self.data_length.is_some() {
+            io::copy(&mut reader, &mut io::sink())
+                .ok()
+                .map(|len| len as usize)
+        } else {
+            None
+        };
+
+        let mut headers = self.headers;
+        if (200..300).contains(&self.status_code.0) {
+            weaken_etags(&mut headers);
+        }
+        headers.push(
+            Header::from_bytes(&b"Content-Encoding"[..], encoding.name().as_bytes()).unwrap(),
+        );
+
+        Response {
+            reader: Box::new(reader) as Box<dyn Read + Send>,
+            status_code: self.status_code,
+            headers,
+            data_length,
+            chunked_threshold: self.chunked_threshold,
+            streaming: self.streaming,
         }
     }
 }
@@ -569,6 +868,7 @@ impl Clone for Response<io::Empty> {
             headers: self.headers.clone(),
             data_length: self.data_length,
             chunked_threshold: self.chunked_threshold,
+            streaming: self.streaming,
         }
     }
 }
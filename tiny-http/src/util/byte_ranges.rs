@@ -0,0 +1,219 @@
+use std::collections::VecDeque;
+use std::io::Result as IoResult;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::ops::Range;
+
+/// Requests with more ranges than this are answered with the full body.
+const MAX_RANGES: usize = 64;
+
+/// Parses the value of a `Range` header for an entity of `len` bytes.
+///
+/// Returns `None` if the header must be ignored (unknown unit, invalid syntax, too many
+/// ranges), in which case the full body should be sent. An empty list means that none
+/// of the ranges are satisfiable. Ranges are returned as half-open byte ranges.
+pub fn parse_range_header(value: &str, len: u64) -> Option<Vec<Range<u64>>> {
+    let (unit, specs) = value.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',') {
+        let spec = spec.trim();
+        if spec.is_empty() {
+            continue;
+        }
+        let (first, last) = spec.split_once('-')?;
+        let (first, last) = (first.trim(), last.trim());
+
+        let range = if first.is_empty() {
+            // suffix range: the last N bytes
+            let suffix: u64 = last.parse().ok()?;
+            len.saturating_sub(suffix)..len
+        } else {
+            let first: u64 = first.parse().ok()?;
+            let last = if last.is_empty() {
+                u64::MAX
+            } else {
+                last.parse().ok()?
+            };
+            if last < first {
+                return None;
+            }
+            first..last.saturating_add(1).min(len)
+        };
+
+        // unsatisfiable ranges are skipped, the others are still served
+        if range.start < range.end {
+            ranges.push(range);
+        }
+        if ranges.len() > MAX_RANGES {
+            return None;
+        }
+    }
+
+    Some(ranges)
+}
+
+enum Segment {
+    Data(Cursor<Vec<u8>>),
+    Range {
+        start: u64,
+        remaining: u64,
+        seeked: bool,
+    },
+}
+
+/// Reads byte ranges out of a seekable reader, optionally separated by the part headers
+/// of a `multipart/byteranges` body.
+pub struct ByteRangesReader<R> {
+    reader: R,
+    segments: VecDeque<Segment>,
+    len: u64,
+}
+
+impl<R> ByteRangesReader<R>
+where
+    R: Read + Seek,
+{
+    /// Reads a single range.
+    pub fn single(reader: R, range: Range<u64>) -> ByteRangesReader<R> {
+        let mut ranges = ByteRangesReader {
+            reader,
+            segments: VecDeque::new(),
+            len: 0,
+        };
+        ranges.push_range(range);
+        ranges
+    }
+
+    /// Builds a `multipart/byteranges` body delimited by `boundary`.
+    pub fn multipart(
+        reader: R,
+        ranges: &[Range<u64>],
+        boundary: &str,
+        content_type: Option<&str>,
+        total_len: u64,
+    ) -> ByteRangesReader<R> {
+        let mut body = ByteRangesReader {
+            reader,
+            segments: VecDeque::new(),
+            len: 0,
+        };
+
+        for range in ranges {
+            let mut head = format!("\r\n--{}\r\n", boundary);
+            if let Some(content_type) = content_type {
+                head.push_str(&format!("Content-Type: {}\r\n", content_type));
+            }
+            head.push_str(&format!(
+                "Content-Range: bytes {}-{}/{}\r\n\r\n",
+                range.start,
+                range.end - 1,
+                total_len
+            ));
+            body.push_data(head.into_bytes());
+            body.push_range(range.clone());
+        }
+        body.push_data(format!("\r\n--{}--\r\n", boundary).into_bytes());
+
+        body
+    }
+
+    /// Total number of bytes that will be read.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn push_data(&mut self, data: Vec<u8>) {
+        self.len += data.len() as u64;
+        self.segments.push_back(Segment::Data(Cursor::new(data)));
+    }
+
+    fn push_range(&mut self, range: Range<u64>) {
+        self.len += range.end - range.start;
+        self.segments.push_back(Segment::Range {
+            start: range.start,
+            remaining: range.end - range.start,
+            seeked: false,
+        });
+    }
+}
+
+impl<R> Read for ByteRangesReader<R>
+where
+    R: Read + Seek,
+{
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        while let Some(segment) = self.segments.front_mut() {
+            let read = match segment {
+                Segment::Data(data) => data.read(buf)?,
+                Segment::Range {
+                    start,
+                    remaining,
+                    seeked,
+                } => {
+                    if !*seeked {
+                        self.reader.seek(SeekFrom::Start(*start))?;
+                        *seeked = true;
+                    }
+                    let len = buf.len().min(*remaining as usize);
+                    let read = self.reader.read(&mut buf[..len])?;
+                    if read == 0 && len > 0 {
+                        return Err(std::io::ErrorKind::UnexpectedEof.into());
+                    }
+                    *remaining -= read as u64;
+                    read
+                }
+            };
+
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            self.segments.pop_front();
+        }
+
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_range_header, ByteRangesReader};
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn parse_ranges() {
+        assert_eq!(parse_range_header("bytes=0-9", 100), Some(vec![0..10]));
+        assert_eq!(parse_range_header("bytes=90-", 100), Some(vec![90..100]));
+        assert_eq!(parse_range_header("bytes=-10", 100), Some(vec![90..100]));
+        assert_eq!(parse_range_header("bytes=-200", 100), Some(vec![0..100]));
+        assert_eq!(parse_range_header("bytes=95-200", 100), Some(vec![95..100]));
+        assert_eq!(
+            parse_range_header("bytes=0-0, 10-19", 100),
+            Some(vec![0..1, 10..20])
+        );
+        assert_eq!(parse_range_header("bytes=100-", 100), Some(vec![]));
+        assert_eq!(parse_range_header("bytes=-0", 100), Some(vec![]));
+        assert_eq!(parse_range_header("bytes=9-0", 100), None);
+        assert_eq!(parse_range_header("items=0-9", 100), None);
+        assert_eq!(parse_range_header("bytes=a-b", 100), None);
+    }
+
+    #[test]
+    fn multipart_body() {
+        let data = Cursor::new(b"0123456789".to_vec());
+        let mut reader = ByteRangesReader::multipart(data, &[0..2, 8..10], "B", None, 10);
+        let len = reader.len();
+
+        let mut body = String::new();
+        reader.read_to_string(&mut body).unwrap();
+        assert_eq!(
+            body,
+            "\r\n--B\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+             \r\n--B\r\nContent-Range: bytes 8-9/10\r\n\r\n89\
+             \r\n--B--\r\n"
+        );
+        assert_eq!(body.len() as u64, len);
+    }
+}
@@ -0,0 +1,79 @@
+use std::io::Error as IoError;
+use std::io::{ErrorKind, Read, Result as IoResult};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Wraps another reader and fails once more than `limit` bytes come out of it.
+///
+/// `exceeded` is set when that happens, so that the error can be told apart from
+/// errors of the inner reader.
+pub struct LimitedReader<R> {
+    inner: R,
+    remaining: usize,
+    exceeded: Arc<AtomicBool>,
+}
+
+impl<R: Read> LimitedReader<R> {
+    pub fn new(inner: R, limit: usize, exceeded: Arc<AtomicBool>) -> LimitedReader<R> {
+        LimitedReader {
+            inner,
+            remaining: limit,
+            exceeded,
+        }
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.remaining == 0 {
+            // the data may end right at the limit
+            if self.inner.read(&mut [0])? == 0 {
+                return Ok(0);
+            }
+            self.exceeded.store(true, Ordering::Relaxed);
+            return Err(IoError::new(
+                ErrorKind::InvalidData,
+                "Request body is larger than the limit",
+            ));
+        }
+
+        let len = buf.len().min(self.remaining);
+        let read = self.inner.read(&mut buf[..len])?;
+        self.remaining -= read;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LimitedReader;
+    use std::io::Read;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn data_within_limit() {
+        let exceeded = Arc::new(AtomicBool::new(false));
+        let mut reader = LimitedReader::new(&b"hello"[..], 5, exceeded.clone());
+
+        let mut data = String::new();
+        reader.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "hello");
+        assert!(!exceeded.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn data_over_limit() {
+        let exceeded = Arc::new(AtomicBool::new(false));
+        let mut reader = LimitedReader::new(&b"hello world"[..], 5, exceeded.clone());
+
+        let mut data = Vec::new();
+        assert!(reader.read_to_end(&mut data).is_err());
+        assert_eq!(data, b"hello");
+        assert!(exceeded.load(Ordering::Relaxed));
+    }
+}
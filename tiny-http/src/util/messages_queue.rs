@@ -13,16 +13,20 @@ where
 {
     queue: Mutex<VecDeque<Control<T>>>,
     condvar: Condvar,
+    // maximum number of elements `try_push` lets in the queue
+    limit: Option<usize>,
 }
 
 impl<T> MessagesQueue<T>
 where
     T: Send,
 {
-    pub fn with_capacity(capacity: usize) -> Arc<MessagesQueue<T>> {
+    /// Builds a queue where `try_push` fails once `limit` elements are waiting.
+    pub fn with_limit(capacity: usize, limit: Option<usize>) -> Arc<MessagesQueue<T>> {
         Arc::new(MessagesQueue {
             queue: Mutex::new(VecDeque::with_capacity(capacity)),
             condvar: Condvar::new(),
+            limit,
         })
     }
 
@@ -33,6 +37,18 @@ where
         self.condvar.notify_one();
     }
 
+    /// Pushes an element to the queue unless it is full.
+    /// Gives the element back if the queue already holds `limit` elements.
+    pub fn try_push(&self, value: T) -> Result<(), T> {
+        let mut queue = self.queue.lock().unwrap();
+        if self.limit.map_or(false, |limit| queue.len() >= limit) {
+            return Err(value);
+        }
+        queue.push_back(Control::Elem(value));
+        self.condvar.notify_one();
+        Ok(())
+    }
+
     /// Unblock one thread stuck in pop loop.
     pub fn unblock(&self) {
         let mut queue = self.queue.lock().unwrap();
@@ -94,3 +110,25 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::MessagesQueue;
+
+    #[test]
+    fn try_push_respects_limit() {
+        let queue = MessagesQueue::with_limit(2, Some(2));
+        assert!(queue.try_push(1).is_ok());
+        assert!(queue.try_push(2).is_ok());
+        assert_eq!(queue.try_push(3), Err(3));
+
+        // `push` isn't limited
+        queue.push(4);
+        assert_eq!(queue.try_pop(), Some(1));
+        assert_eq!(queue.try_push(5), Err(5));
+        assert_eq!(queue.try_pop(), Some(2));
+        assert!(queue.try_push(5).is_ok());
+        assert_eq!(queue.try_pop(), Some(4));
+        assert_eq!(queue.try_pop(), Some(5));
+    }
+}
@@ -1,17 +1,21 @@
+pub use self::byte_ranges::{parse_range_header, ByteRangesReader};
 pub use self::custom_stream::CustomStream;
 pub use self::equal_reader::EqualReader;
 pub use self::fused_reader::FusedReader;
+pub use self::limited_reader::LimitedReader;
 pub use self::messages_queue::MessagesQueue;
 pub use self::refined_tcp_stream::RefinedTcpStream;
-pub use self::sequential::{SequentialReader, SequentialReaderBuilder};
 pub use self::sequential::SequentialWriterBuilder;
+pub use self::sequential::{SequentialReader, SequentialReaderBuilder};
 pub use self::task_pool::TaskPool;
 
 use std::str::FromStr;
 
+mod byte_ranges;
 mod custom_stream;
 mod equal_reader;
 mod fused_reader;
+mod limited_reader;
 mod messages_queue;
 pub(crate) mod refined_tcp_stream;
 mod sequential;
@@ -71,6 +71,11 @@ impl<W: Write + Send> SequentialWriterBuilder<W> {
             next_trigger: None,
         }
     }
+
+    /// Returns true if a writer built by this builder is still alive.
+    pub fn has_pending_writer(&self) -> bool {
+        Arc::strong_count(&self.writer) > 1
+    }
 }
 
 impl<R: Read + Send> Iterator for SequentialReaderBuilder<R> {
@@ -0,0 +1,469 @@
+//! Websocket support on top of `Request::upgrade_split`.
+//!
+//! This handles the opening handshake, frame encoding and decoding, ping/pong and the
+//! closing handshake (RFC 6455). Extensions such as compression are not supported.
+
+use std::io;
+use std::io::Error as IoError;
+use std::io::Result as IoResult;
+use std::io::{ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::{Header, Request, Response, StatusCode};
+
+/// GUID appended to the client's key to compute `Sec-WebSocket-Accept`.
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The only version of the protocol, sent back when the client asks for another one.
+const WEBSOCKET_VERSION: &str = "13";
+
+/// Default limit for the size of a single (possibly fragmented) incoming message.
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// Close status codes from RFC 6455 section 7.4.1.
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+pub const CLOSE_INTERNAL_ERROR: u16 = 1011;
+
+/// A message sent or received over a websocket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WebSocketMessage {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// Closing handshake, with an optional status code and reason.
+    Close(Option<(u16, String)>),
+}
+
+impl Request {
+    /// Returns true if the client asked to upgrade the connection to a websocket.
+    pub fn is_websocket_upgrade(&self) -> bool {
+        fn has_token(headers: &[Header], name: &'static str, token: &str) -> bool {
+            headers
+                .iter()
+                .filter(|h| h.field.equiv(name))
+                .flat_map(|h| h.value.as_str().split(','))
+                .any(|v| v.trim().eq_ignore_ascii_case(token))
+        }
+
+        has_token(self.headers(), "Upgrade", "websocket")
+            && has_token(self.headers(), "Connection", "upgrade")
+    }
+
+    /// Returns true if the client asked for version 13 of the websocket protocol, the
+    /// only one supported.
+    ///
+    /// A handshake asking for another version should be answered with
+    /// `websocket::version_not_supported`.
+    pub fn has_supported_websocket_version(&self) -> bool {
+        self.headers()
+            .iter()
+            .find(|h| h.field.equiv("Sec-WebSocket-Version"))
+            .map_or(false, |h| h.value.as_str().trim() == WEBSOCKET_VERSION)
+    }
+
+    /// Answers the websocket opening handshake and turns the request into a websocket.
+    ///
+    /// `protocol` is sent back as `Sec-WebSocket-Protocol` if specified.
+    ///
+    /// If the request is not a valid websocket upgrade (see `is_websocket_upgrade`), the
+    /// client did not send a `Sec-WebSocket-Key`, or it asked for an unsupported version (see
+    /// `has_supported_websocket_version`), the request is given back untouched so that it can
+    /// be answered normally (usually with a 400, or a 426 for the version).
+    #[allow(clippy::result_large_err)]
+    pub fn into_websocket(
+        self,
+        protocol: Option<&str>,
+    ) -> Result<(WebSocketReceiver, WebSocketSender), Request> {
+        if !self.is_websocket_upgrade() || !self.has_supported_websocket_version() {
+            return Err(self);
+        }
+
+        let key = match self
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Sec-WebSocket-Key"))
+        {
+            Some(h) => h.value.as_str().trim().to_owned(),
+            None => return Err(self),
+        };
+
+        let mut response = Response::empty(StatusCode(101)).with_header(
+            Header::from_bytes(&b"Sec-WebSocket-Accept"[..], accept_key(&key).as_bytes()).unwrap(),
+        );
+        if let Some(protocol) = protocol {
+            if let Ok(header) = Header::from_bytes(&b"Sec-WebSocket-Protocol"[..], protocol) {
+                response.add_header(header);
+            }
+        }
+
+        let (reader, writer) = self.upgrade_split("websocket", response);
+        let sender = WebSocketSender {
+            state: Arc::new(Mutex::new(SenderState {
+                writer,
+                closed: false,
+            })),
+        };
+        let receiver = WebSocketReceiver {
+            reader,
+            sender: sender.clone(),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            closed: false,
+        };
+
+        Ok((receiver, sender))
+    }
+}
+
+/// Answers a handshake asking for an unsupported version of the protocol with
+/// 426 Upgrade Required and the supported version (RFC 6455 section 4.4).
+pub fn version_not_supported() -> Response<io::Empty> {
+    Response::empty(StatusCode(426)).with_header(
+        Header::from_bytes(&b"Sec-WebSocket-Version"[..], WEBSOCKET_VERSION.as_bytes()).unwrap(),
+    )
+}
+
+/// Computes the `Sec-WebSocket-Accept` value for a `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = sha1::Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(HANDSHAKE_GUID.as_bytes());
+    base64_encode(&sha1.digest().bytes())
+}
+
+fn base64_encode(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut output = String::with_capacity((input.len() + 2) / 3 * 4);
+    for chunk in input.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+
+        output.push(ALPHABET[(n >> 18) as usize & 63] as char);
+        output.push(ALPHABET[(n >> 12) as usize & 63] as char);
+        if chunk.len() > 1 {
+            output.push(ALPHABET[(n >> 6) as usize & 63] as char);
+        } else {
+            output.push('=');
+        }
+        if chunk.len() > 2 {
+            output.push(ALPHABET[n as usize & 63] as char);
+        } else {
+            output.push('=');
+        }
+    }
+    output
+}
+
+/// Reading half of a websocket.
+///
+/// Pings are answered and the closing handshake is completed automatically, using the
+/// matching `WebSocketSender`.
+pub struct WebSocketReceiver {
+    reader: Box<dyn Read + Send>,
+    sender: WebSocketSender,
+    max_message_size: usize,
+    closed: bool,
+}
+
+/// Writing half of a websocket. Can be cloned to send from several threads.
+#[derive(Clone)]
+pub struct WebSocketSender {
+    state: Arc<Mutex<SenderState>>,
+}
+
+struct SenderState {
+    writer: Box<dyn Write + Send>,
+    // true once a close frame has been sent, after which nothing else may be sent
+    closed: bool,
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+impl WebSocketReceiver {
+    /// Sets the maximum size of an incoming message, fragments included.
+    ///
+    /// Bigger messages close the connection with status 1009. The default is 16 MiB.
+    pub fn with_max_message_size(mut self, size: usize) -> WebSocketReceiver {
+        self.max_message_size = size;
+        self
+    }
+
+    /// Blocks until the next text or binary message is received.
+    ///
+    /// Returns `Ok(None)` once the connection has been closed by either side.
+    /// Protocol violations close the connection with the appropriate status code and are
+    /// returned as errors.
+    pub fn recv(&mut self) -> IoResult<Option<WebSocketMessage>> {
+        if self.closed {
+            return Ok(None);
+        }
+
+        // opcode and data of a fragmented message being received
+        let mut message: Option<(u8, Vec<u8>)> = None;
+
+        loop {
+            let frame = match self.read_frame() {
+                Ok(frame) => frame,
+                Err(ref err) if err.kind() == ErrorKind::UnexpectedEof => {
+                    self.closed = true;
+                    return Ok(None);
+                }
+                Err(err) => {
+                    self.closed = true;
+                    return Err(err);
+                }
+            };
+
+            match frame.opcode {
+                OPCODE_PING => {
+                    self.sender
+                        .send(&WebSocketMessage::Pong(frame.payload))
+                        .ok();
+                    continue;
+                }
+                OPCODE_PONG => continue,
+                OPCODE_CLOSE => {
+                    self.closed = true;
+                    // echoing the status code back, as recommended by RFC 6455
+                    let code = match frame.payload[..] {
+                        [] => CLOSE_NORMAL,
+                        [high, low, ..] => u16::from_be_bytes([high, low]),
+                        [_] => CLOSE_PROTOCOL_ERROR,
+                    };
+                    self.sender.close(code, "").ok();
+                    return Ok(None);
+                }
+                OPCODE_TEXT | OPCODE_BINARY if message.is_none() => {
+                    message = Some((frame.opcode, frame.payload));
+                }
+                OPCODE_CONTINUATION if message.is_some() => {
+                    let (_, data) = message.as_mut().unwrap();
+                    if data.len() + frame.payload.len() > self.max_message_size {
+                        return Err(self.fail_with(CLOSE_MESSAGE_TOO_BIG, "message too big"));
+                    }
+                    data.extend_from_slice(&frame.payload);
+                }
+                _ => return Err(self.fail_with(CLOSE_PROTOCOL_ERROR, "unexpected frame")),
+            }
+
+            if !frame.fin {
+                continue;
+            }
+
+            return match message.take() {
+                Some((OPCODE_TEXT, data)) => match String::from_utf8(data) {
+                    Ok(text) => Ok(Some(WebSocketMessage::Text(text))),
+                    Err(_) => Err(self.fail_with(CLOSE_INVALID_DATA, "text is not valid UTF-8")),
+                },
+                Some((_, data)) => Ok(Some(WebSocketMessage::Binary(data))),
+                None => unreachable!(),
+            };
+        }
+    }
+
+    /// Reads a single frame, unmasking its payload.
+    fn read_frame(&mut self) -> IoResult<Frame> {
+        let mut head = [0; 2];
+        self.reader.read_exact(&mut head)?;
+
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0F;
+        let masked = head[1] & 0x80 != 0;
+
+        if head[0] & 0x70 != 0 {
+            return Err(self.fail_with(CLOSE_PROTOCOL_ERROR, "unexpected reserved bits"));
+        }
+        // clients must mask every frame (RFC 6455 section 5.1)
+        if !masked {
+            return Err(self.fail_with(CLOSE_PROTOCOL_ERROR, "unmasked client frame"));
+        }
+
+        let length = match head[1] & 0x7F {
+            126 => {
+                let mut buf = [0; 2];
+                self.reader.read_exact(&mut buf)?;
+                u64::from(u16::from_be_bytes(buf))
+            }
+            127 => {
+                let mut buf = [0; 8];
+                self.reader.read_exact(&mut buf)?;
+                u64::from_be_bytes(buf)
+            }
+            length => u64::from(length),
+        };
+
+        // control frames are always small and never fragmented
+        if opcode >= OPCODE_CLOSE && (length > 125 || !fin) {
+            return Err(self.fail_with(CLOSE_PROTOCOL_ERROR, "invalid control frame"));
+        }
+        if length > self.max_message_size as u64 {
+            return Err(self.fail_with(CLOSE_MESSAGE_TOO_BIG, "message too big"));
+        }
+
+        let mut mask = [0; 4];
+        self.reader.read_exact(&mut mask)?;
+
+        let mut payload = vec![0; length as usize];
+        self.reader.read_exact(&mut payload)?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+
+        Ok(Frame {
+            fin,
+            opcode,
+            payload,
+        })
+    }
+
+    /// Closes the connection with the given status code after a protocol violation.
+    fn fail_with(&mut self, code: u16, reason: &str) -> IoError {
+        self.closed = true;
+        self.sender.close(code, reason).ok();
+        IoError::new(ErrorKind::InvalidData, reason.to_owned())
+    }
+}
+
+impl Iterator for WebSocketReceiver {
+    type Item = WebSocketMessage;
+
+    /// Returns the next text or binary message, or `None` once the connection is closed
+    /// or broken.
+    fn next(&mut self) -> Option<WebSocketMessage> {
+        self.recv().ok().flatten()
+    }
+}
+
+impl WebSocketSender {
+    /// Sends a message as a single frame.
+    ///
+    /// Sending `WebSocketMessage::Close` is equivalent to calling `close`. If writing fails,
+    /// the sender is marked as closed.
+    pub fn send(&self, message: &WebSocketMessage) -> IoResult<()> {
+        let (opcode, payload) = match message {
+            WebSocketMessage::Text(text) => (OPCODE_TEXT, text.as_bytes()),
+            WebSocketMessage::Binary(data) => (OPCODE_BINARY, &data[..]),
+            WebSocketMessage::Ping(data) => (OPCODE_PING, &data[..]),
+            WebSocketMessage::Pong(data) => (OPCODE_PONG, &data[..]),
+            WebSocketMessage::Close(Some((code, reason))) => return self.close(*code, reason),
+            WebSocketMessage::Close(None) => return self.close(CLOSE_NORMAL, ""),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(IoError::new(
+                ErrorKind::NotConnected,
+                "websocket has been closed",
+            ));
+        }
+
+        // a failed write means the connection is gone, so there is no point in trying again
+        let result = write_frame(&mut state.writer, opcode, payload);
+        if result.is_err() {
+            state.closed = true;
+        }
+        result
+    }
+
+    /// Starts (or completes) the closing handshake. Does nothing if already closed.
+    pub fn close(&self, code: u16, reason: &str) -> IoResult<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Ok(());
+        }
+        state.closed = true;
+
+        // control frames are limited to 125 bytes of payload
+        let mut reason = reason;
+        while reason.len() > 123 {
+            let mut end = reason.len() - 1;
+            while !reason.is_char_boundary(end) {
+                end -= 1;
+            }
+            reason = &reason[..end];
+        }
+
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        write_frame(&mut state.writer, OPCODE_CLOSE, &payload)
+    }
+
+    /// Returns true once a close frame has been sent.
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+}
+
+/// Writes a single unmasked frame (servers never mask) and flushes it.
+fn write_frame<W: Write + ?Sized>(writer: &mut W, opcode: u8, payload: &[u8]) -> IoResult<()> {
+    let mut head = Vec::with_capacity(10);
+    head.push(0x80 | opcode);
+
+    match payload.len() {
+        len if len < 126 => head.push(len as u8),
+        len if len <= usize::from(u16::MAX) => {
+            head.push(126);
+            head.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            head.push(127);
+            head.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+
+    writer.write_all(&head)?;
+    writer.write_all(payload)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accept_key, base64_encode, write_frame, OPCODE_TEXT};
+
+    #[test]
+    fn test_accept_key() {
+        // example from RFC 6455 section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_base64_padding() {
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+    }
+
+    #[test]
+    fn test_write_frame_lengths() {
+        let mut out = Vec::new();
+        write_frame(&mut out, OPCODE_TEXT, b"Hello").unwrap();
+        assert_eq!(out, [0x81, 0x05, b'H', b'e', b'l', b'l', b'o']);
+
+        let mut out = Vec::new();
+        write_frame(&mut out, OPCODE_TEXT, &[0; 256]).unwrap();
+        assert_eq!(&out[..4], &[0x81, 126, 0x01, 0x00]);
+        assert_eq!(out.len(), 4 + 256);
+    }
+}
@@ -120,3 +120,61 @@ fn custom_content_type_response_header() {
     assert!(content.ends_with("{\"custom\": \"Content-Type\"}"));
     assert_ne!(content.find("Content-Type: application/json"), None);
 }
+
+#[test]
+fn take_reader_sends_continue_on_first_read() {
+    let (server, client) = support::new_one_server_one_client();
+
+    let mut client = client;
+    (write!(client, "POST / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nExpect: 100-continue\r\nContent-Type: text/plain; charset=utf8\r\nContent-Length: 5\r\n\r\n")).unwrap();
+    client.flush().unwrap();
+
+    let mut request = server.recv().unwrap();
+    let mut body = request.take_reader();
+
+    let reader = thread::spawn(move || {
+        let mut output = String::new();
+        body.read_to_string(&mut output).unwrap();
+        output
+    });
+
+    let mut content = vec![0; 12];
+    client.read_exact(&mut content).unwrap();
+    assert!(&content[9..].starts_with(b"100")); // 100 status code
+
+    (write!(client, "hello")).unwrap();
+    client.flush().unwrap();
+
+    assert_eq!(reader.join().unwrap(), "hello");
+    request
+        .respond(tiny_http::Response::from_string("done"))
+        .unwrap();
+
+    let mut content = String::new();
+    client.read_to_string(&mut content).unwrap();
+    assert!(content.contains("200 OK"));
+    assert!(content.ends_with("done"));
+}
+
+#[test]
+fn take_reader_skips_continue_after_response() {
+    let (server, client) = support::new_one_server_one_client();
+
+    let mut client = client;
+    (write!(client, "POST / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n")).unwrap();
+    client.flush().unwrap();
+
+    let mut request = server.recv().unwrap();
+    let body = request.take_reader();
+    request.respond(tiny_http::Response::empty(413)).unwrap();
+
+    // the body is still drained once the reader is dropped
+    (write!(client, "hello")).unwrap();
+    client.flush().unwrap();
+    drop(body);
+
+    let mut content = String::new();
+    client.read_to_string(&mut content).unwrap();
+    assert!(content.starts_with("HTTP/1.1 413"));
+    assert!(!content.contains("100 Continue"));
+}
@@ -0,0 +1,263 @@
+extern crate tiny_http;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use tiny_http::{ConfigListenAddr, Server, ServerConfig, ServerLimits};
+
+/// Creates a server with the given limits, and returns it with its port.
+fn new_server(limits: ServerLimits) -> (Server, u16) {
+    let server = Server::new(ServerConfig {
+        addr: ConfigListenAddr::from_socket_addrs("127.0.0.1:0").unwrap(),
+        ssl: None,
+        limits,
+    })
+    .unwrap();
+    let port = server.server_addr().to_ip().unwrap().port();
+    (server, port)
+}
+
+fn connect(port: u16) -> TcpStream {
+    let client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    client
+}
+
+#[test]
+fn full_queue_rejects_requests() {
+    let (server, port) = new_server(ServerLimits {
+        max_pending_requests: Some(1),
+        retry_after: Some(Duration::from_secs(2)),
+        ..ServerLimits::default()
+    });
+
+    let mut first = connect(port);
+    write!(first, "GET /first HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    let mut second = connect(port);
+    write!(
+        second,
+        "GET /second HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"
+    )
+    .unwrap();
+    let mut response = String::new();
+    second.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 503"));
+    assert!(response.contains("Retry-After: 2"));
+
+    // the queued request is still served
+    let request = server.recv().unwrap();
+    assert_eq!(request.url(), "/first");
+    assert!(server.try_recv().unwrap().is_none());
+}
+
+#[test]
+fn slow_request_head_times_out() {
+    let (_server, port) = new_server(ServerLimits {
+        header_read_timeout: Some(Duration::from_millis(200)),
+        ..ServerLimits::default()
+    });
+
+    let mut client = connect(port);
+    write!(client, "GET / HTTP/1.1\r\nHost: local").unwrap();
+
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 408"));
+}
+
+#[test]
+fn idle_connection_is_closed() {
+    let (server, port) = new_server(ServerLimits {
+        keep_alive_timeout: Some(Duration::from_millis(200)),
+        ..ServerLimits::default()
+    });
+
+    let mut client = connect(port);
+    write!(client, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let request = server.recv().unwrap();
+    request
+        .respond(tiny_http::Response::from_string("hello"))
+        .unwrap();
+
+    // the response, then the connection is closed without a 408
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(response.ends_with("hello"));
+}
+
+#[test]
+fn connection_closes_after_max_requests() {
+    let (server, port) = new_server(ServerLimits {
+        max_requests_per_connection: Some(2),
+        ..ServerLimits::default()
+    });
+
+    let mut client = connect(port);
+    write!(
+        client,
+        "GET /1 HTTP/1.1\r\nHost: localhost\r\n\r\nGET /2 HTTP/1.1\r\nHost: localhost\r\n\r\n"
+    )
+    .unwrap();
+    for _ in 0..2 {
+        let request = server.recv().unwrap();
+        let body = request.url().to_owned();
+        request
+            .respond(tiny_http::Response::from_string(body))
+            .unwrap();
+    }
+
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    assert_eq!(response.matches("Connection: close").count(), 1);
+    assert!(response.ends_with("/2"));
+}
+
+#[test]
+fn connections_beyond_max_are_closed() {
+    let (server, port) = new_server(ServerLimits {
+        max_connections: Some(1),
+        ..ServerLimits::default()
+    });
+
+    let mut first = connect(port);
+    thread::sleep(Duration::from_millis(100));
+
+    let mut second = connect(port);
+    write!(second, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").ok();
+    let mut response = Vec::new();
+    let _ = second.read_to_end(&mut response);
+    assert!(response.is_empty());
+
+    // the first connection is still served
+    write!(first, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let request = server.recv().unwrap();
+    assert_eq!(request.url(), "/");
+}
+
+#[test]
+fn long_url_is_rejected() {
+    let (_server, port) = new_server(ServerLimits {
+        max_url_length: Some(16),
+        ..ServerLimits::default()
+    });
+
+    let mut client = connect(port);
+    write!(
+        client,
+        "GET /{} HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        "a".repeat(100)
+    )
+    .unwrap();
+
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 414"));
+}
+
+#[test]
+fn large_headers_are_rejected() {
+    let (_server, port) = new_server(ServerLimits {
+        max_header_bytes: Some(256),
+        ..ServerLimits::default()
+    });
+
+    let mut client = connect(port);
+    write!(
+        client,
+        "GET / HTTP/1.1\r\nHost: localhost\r\nX-Big: {}\r\n\r\n",
+        "a".repeat(300)
+    )
+    .unwrap();
+
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 431"));
+}
+
+#[test]
+fn too_many_headers_are_rejected() {
+    let (_server, port) = new_server(ServerLimits {
+        max_header_count: Some(2),
+        ..ServerLimits::default()
+    });
+
+    let mut client = connect(port);
+    write!(
+        client,
+        "GET / HTTP/1.1\r\nHost: localhost\r\nX-A: 1\r\nX-B: 2\r\n\r\n"
+    )
+    .unwrap();
+
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 431"));
+}
+
+#[test]
+fn large_body_is_rejected() {
+    let (_server, port) = new_server(ServerLimits {
+        max_body_size: Some(8),
+        ..ServerLimits::default()
+    });
+
+    let mut client = connect(port);
+    write!(
+        client,
+        "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 9\r\n\r\n123456789"
+    )
+    .unwrap();
+
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 413"));
+}
+
+#[test]
+fn large_chunked_body_fails_to_read() {
+    let (server, port) = new_server(ServerLimits {
+        max_body_size: Some(8),
+        ..ServerLimits::default()
+    });
+
+    let mut client = connect(port);
+    write!(
+        client,
+        "POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+         5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n"
+    )
+    .unwrap();
+
+    let mut request = server.recv().unwrap();
+    let mut body = Vec::new();
+    assert!(request.as_reader().read_to_end(&mut body).is_err());
+    assert!(request.body_too_large());
+}
+
+#[test]
+fn slow_body_fails_to_read() {
+    let (server, port) = new_server(ServerLimits {
+        body_read_timeout: Some(Duration::from_millis(200)),
+        ..ServerLimits::default()
+    });
+
+    // too large to be read before the request is received
+    let mut client = connect(port);
+    write!(
+        client,
+        "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2000\r\n\r\nhello"
+    )
+    .unwrap();
+
+    let mut request = server.recv().unwrap();
+    let mut body = Vec::new();
+    assert!(request.as_reader().read_to_end(&mut body).is_err());
+    assert!(request.body_timed_out());
+    assert!(!request.body_too_large());
+}
@@ -2,6 +2,7 @@ extern crate tiny_http;
 
 use std::io::{Read, Write};
 use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
 
@@ -220,3 +221,58 @@ fn chunked_threshold() {
     assert_eq!(resp.chunked_threshold(), 32768);
     assert_eq!(resp.with_chunked_threshold(42).chunked_threshold(), 42);
 }
+
+/// Reader that yields each message received on a channel as a separate read
+struct ChannelReader {
+    receiver: mpsc::Receiver<Vec<u8>>,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.receiver.recv() {
+            Ok(data) => {
+                buf[..data.len()].copy_from_slice(&data);
+                Ok(data.len())
+            }
+            Err(_) => Ok(0),
+        }
+    }
+}
+
+#[test]
+fn streaming_response_flushes_each_read() {
+    let (server, mut client) = support::new_one_server_one_client();
+
+    (write!(
+        client,
+        "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"
+    ))
+    .unwrap();
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let rq = server.recv().unwrap();
+        let resp = tiny_http::Response::empty(200)
+            .with_data(ChannelReader { receiver }, None)
+            .with_streaming(true);
+        rq.respond(resp).unwrap();
+    });
+
+    sender.send(b"first".to_vec()).unwrap();
+
+    // the first chunk must arrive while the reader is still open
+    let mut content = Vec::new();
+    let mut buf = [0; 1024];
+    while !String::from_utf8_lossy(&content).contains("first") {
+        let read = client.read(&mut buf).unwrap();
+        assert!(read > 0);
+        content.extend_from_slice(&buf[..read]);
+    }
+    assert!(String::from_utf8_lossy(&content).contains("Transfer-Encoding: chunked"));
+
+    sender.send(b"second".to_vec()).unwrap();
+    drop(sender);
+
+    client.read_to_end(&mut content).unwrap();
+    assert!(String::from_utf8_lossy(&content).ends_with("6\r\nsecond\r\n0\r\n\r\n"));
+}
@@ -0,0 +1,96 @@
+extern crate tiny_http;
+
+use std::io::{Read, Write};
+
+#[allow(dead_code)]
+mod support;
+
+/// Sends a request with the given extra headers and returns the raw response.
+fn respond_with_ranges<R>(extra_headers: &str, response: tiny_http::Response<R>) -> String
+where
+    R: Read + std::io::Seek + Send + 'static,
+{
+    let (server, mut stream) = support::new_one_server_one_client();
+    write!(
+        stream,
+        "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}\r\n",
+        extra_headers
+    )
+    .unwrap();
+
+    let request = server.recv().unwrap();
+    let response = response.with_ranges(&request);
+    request.respond(response).unwrap();
+
+    let mut content = String::new();
+    stream.read_to_string(&mut content).unwrap();
+    content
+}
+
+#[test]
+fn full_response_advertises_ranges() {
+    let content = respond_with_ranges("", tiny_http::Response::from_string("0123456789"));
+    assert!(content.starts_with("HTTP/1.1 200"));
+    assert!(content.contains("Accept-Ranges: bytes\r\n"));
+    assert!(content.ends_with("\r\n\r\n0123456789"));
+}
+
+#[test]
+fn single_range_from_file() {
+    let path = std::env::temp_dir().join(format!("tiny-http-range-{}", std::process::id()));
+    std::fs::write(&path, b"0123456789").unwrap();
+    let file = std::fs::File::open(&path).unwrap();
+
+    let content = respond_with_ranges("Range: bytes=2-4\r\n", tiny_http::Response::from_file(file));
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(content.starts_with("HTTP/1.1 206"));
+    assert!(content.contains("Content-Range: bytes 2-4/10\r\n"));
+    assert!(content.contains("Content-Length: 3\r\n"));
+    assert!(content.ends_with("\r\n\r\n234"));
+}
+
+#[test]
+fn multiple_ranges() {
+    let response = tiny_http::Response::from_string("0123456789").with_header(
+        "Content-Type: text/plain"
+            .parse::<tiny_http::Header>()
+            .unwrap(),
+    );
+    let content = respond_with_ranges("Range: bytes=0-1,-2\r\n", response);
+
+    assert!(content.starts_with("HTTP/1.1 206"));
+    assert!(content.contains("Content-Type: multipart/byteranges; boundary="));
+    assert!(
+        content.contains("Content-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\r\n")
+    );
+    assert!(
+        content.contains("Content-Type: text/plain\r\nContent-Range: bytes 8-9/10\r\n\r\n89\r\n")
+    );
+}
+
+#[test]
+fn unsatisfiable_range() {
+    let content = respond_with_ranges(
+        "Range: bytes=20-\r\n",
+        tiny_http::Response::from_string("0123456789"),
+    );
+    assert!(content.starts_with("HTTP/1.1 416"));
+    assert!(content.contains("Content-Range: bytes */10\r\n"));
+}
+
+#[test]
+fn if_range_mismatch_sends_full_body() {
+    let response = tiny_http::Response::from_string("0123456789")
+        .with_header("ETag: \"v2\"".parse::<tiny_http::Header>().unwrap());
+
+    let content = respond_with_ranges("Range: bytes=0-1\r\nIf-Range: \"v1\"\r\n", response);
+    assert!(content.starts_with("HTTP/1.1 200"));
+    assert!(content.ends_with("0123456789"));
+
+    let response = tiny_http::Response::from_string("0123456789")
+        .with_header("ETag: \"v2\"".parse::<tiny_http::Header>().unwrap());
+    let content = respond_with_ranges("Range: bytes=0-1\r\nIf-Range: \"v2\"\r\n", response);
+    assert!(content.starts_with("HTTP/1.1 206"));
+    assert!(content.ends_with("\r\n\r\n01"));
+}
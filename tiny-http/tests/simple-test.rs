@@ -27,3 +27,131 @@ fn basic_handling() {
     stream.read_to_string(&mut content).unwrap();
     assert!(content.ends_with("hello world"));
 }
+
+#[test]
+fn head_request_has_length_but_no_body() {
+    let (server, mut stream) = support::new_one_server_one_client();
+    write!(
+        stream,
+        "HEAD / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"
+    )
+    .unwrap();
+
+    // large enough that a GET would be sent chunked
+    let request = server.recv().unwrap();
+    request
+        .respond(tiny_http::Response::from_data(vec![b'a'; 100_000]))
+        .unwrap();
+
+    let mut content = String::new();
+    stream.read_to_string(&mut content).unwrap();
+    assert!(content.contains("Content-Length: 100000\r\n"));
+    assert!(!content.contains("chunked"));
+    assert!(content.ends_with("\r\n\r\n"));
+}
+
+#[test]
+fn head_request_does_not_read_body() {
+    struct Unreadable;
+
+    impl Read for Unreadable {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            panic!("the body of a HEAD response must not be read")
+        }
+    }
+
+    let (server, mut stream) = support::new_one_server_one_client();
+    write!(
+        stream,
+        "HEAD / HTTP/1.0\r\nHost: localhost\r\nConnection: close\r\n\r\n"
+    )
+    .unwrap();
+
+    let request = server.recv().unwrap();
+    request
+        .respond(tiny_http::Response::new(
+            tiny_http::StatusCode(200),
+            vec![],
+            Unreadable,
+            None,
+            None,
+        ))
+        .unwrap();
+
+    let mut content = String::new();
+    stream.read_to_string(&mut content).unwrap();
+    assert!(content.starts_with("HTTP/1.0 200"));
+    assert!(content.ends_with("\r\n\r\n"));
+}
+
+#[test]
+fn compression_without_accept_encoding_adds_vary() {
+    let (server, mut stream) = support::new_one_server_one_client();
+    write!(
+        stream,
+        "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"
+    )
+    .unwrap();
+
+    let request = server.recv().unwrap();
+    let body = "a".repeat(2048);
+    let response = tiny_http::Response::from_string(body.clone())
+        .with_compression(&request, &tiny_http::CompressionConfig::default());
+    request.respond(response).unwrap();
+
+    let mut content = String::new();
+    stream.read_to_string(&mut content).unwrap();
+    assert!(content.contains("Vary: Accept-Encoding\r\n"));
+    assert!(!content.contains("Content-Encoding"));
+    assert!(content.ends_with(&body));
+}
+
+#[test]
+#[cfg(feature = "compression-gzip")]
+fn compressed_head_response_has_length() {
+    let (server, mut stream) = support::new_one_server_one_client();
+    write!(
+        stream,
+        "HEAD / HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: gzip\r\nConnection: close\r\n\r\n"
+    )
+    .unwrap();
+
+    let request = server.recv().unwrap();
+    let response = tiny_http::Response::from_string("a".repeat(2048))
+        .with_compression(&request, &tiny_http::CompressionConfig::default());
+    request.respond(response).unwrap();
+
+    let mut content = String::new();
+    stream.read_to_string(&mut content).unwrap();
+    assert!(content.contains("Content-Encoding: gzip\r\n"));
+    assert!(content.contains("Content-Length: "));
+    assert!(!content.contains("Content-Length: 2048"));
+    assert!(content.ends_with("\r\n\r\n"));
+}
+
+#[test]
+#[cfg(feature = "compression-gzip")]
+fn not_modified_has_compressed_validators() {
+    let (server, mut stream) = support::new_one_server_one_client();
+    write!(
+        stream,
+        "GET / HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: gzip\r\nIf-None-Match: W/\"v1\"\r\nConnection: close\r\n\r\n"
+    )
+    .unwrap();
+
+    let request = server.recv().unwrap();
+    let etag = tiny_http::Header::from_bytes(&b"ETag"[..], &b"\"v1\""[..]).unwrap();
+    let full = tiny_http::Response::from_string("a".repeat(2048)).with_header(etag.clone());
+    let compressible = full.is_compressible(&tiny_http::CompressionConfig::default());
+    let response = tiny_http::Response::empty(304)
+        .with_header(etag)
+        .with_compression_validators(&request, compressible);
+    request.respond(response).unwrap();
+
+    let mut content = String::new();
+    stream.read_to_string(&mut content).unwrap();
+    assert!(content.starts_with("HTTP/1.1 304"));
+    assert!(content.contains("ETag: W/\"v1\"\r\n"));
+    assert!(content.contains("Vary: Accept-Encoding\r\n"));
+    assert!(!content.contains("Content-Encoding"));
+}
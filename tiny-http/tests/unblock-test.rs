@@ -32,3 +32,42 @@ fn unblock_threads() {
     h1.join().unwrap();
     h2.join().unwrap();
 }
+
+#[test]
+fn drain_closes_connections() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let port = server.server_addr().to_ip().unwrap().port();
+
+    let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(client, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let request = server.recv().unwrap();
+
+    server.drain();
+    assert!(server.is_draining());
+    request
+        .respond(tiny_http::Response::from_string("bye"))
+        .unwrap();
+
+    // the response tells the client to close the keep-alive connection
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut response = Vec::new();
+    let mut buf = [0; 256];
+    while !response.ends_with(b"bye") {
+        let read = client.read(&mut buf).unwrap();
+        assert!(read > 0);
+        response.extend_from_slice(&buf[..read]);
+    }
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.contains("Connection: close"));
+    drop(client);
+
+    // and no new connection is accepted
+    thread::sleep(Duration::from_millis(100));
+    assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+}
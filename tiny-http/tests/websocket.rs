@@ -0,0 +1,147 @@
+extern crate tiny_http;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+
+#[allow(dead_code)]
+mod support;
+
+/// Sends a masked frame, as clients must.
+fn send_frame(client: &mut TcpStream, opcode: u8, payload: &[u8]) {
+    let mask = [0x12, 0x34, 0x56, 0x78];
+    let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    client.write_all(&frame).unwrap();
+}
+
+/// Reads an unmasked server frame, returning its opcode and payload.
+fn read_frame(client: &mut TcpStream) -> (u8, Vec<u8>) {
+    let mut head = [0; 2];
+    client.read_exact(&mut head).unwrap();
+    assert_eq!(head[1] & 0x80, 0, "server frames must not be masked");
+    let mut payload = vec![0; (head[1] & 0x7F) as usize];
+    client.read_exact(&mut payload).unwrap();
+    (head[0] & 0x0F, payload)
+}
+
+fn read_response_head(client: &mut TcpStream) -> String {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        client.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    String::from_utf8(head).unwrap()
+}
+
+#[test]
+fn websocket_echo() {
+    let (server, mut client) = support::new_one_server_one_client();
+
+    (write!(
+        client,
+        "GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n"
+    ))
+    .unwrap();
+
+    let server_thread = thread::spawn(move || {
+        let request = server.recv().unwrap();
+        assert!(request.is_websocket_upgrade());
+
+        let (receiver, sender) = request.into_websocket(None).unwrap();
+        for message in receiver {
+            sender.send(&message).unwrap();
+        }
+    });
+
+    let head = read_response_head(&mut client);
+    assert!(head.starts_with("HTTP/1.1 101"));
+    assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+    send_frame(&mut client, 0x1, b"hello");
+    assert_eq!(read_frame(&mut client), (0x1, b"hello".to_vec()));
+
+    send_frame(&mut client, 0x9, b"ping");
+    assert_eq!(read_frame(&mut client), (0xA, b"ping".to_vec()));
+
+    send_frame(&mut client, 0x2, &[1, 2, 3]);
+    assert_eq!(read_frame(&mut client), (0x2, vec![1, 2, 3]));
+
+    send_frame(&mut client, 0x8, &1000u16.to_be_bytes());
+    assert_eq!(
+        read_frame(&mut client),
+        (0x8, 1000u16.to_be_bytes().to_vec())
+    );
+
+    server_thread.join().unwrap();
+}
+
+#[test]
+fn websocket_without_key_is_rejected() {
+    let (server, mut client) = support::new_one_server_one_client();
+
+    (write!(
+        client,
+        "GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n"
+    ))
+    .unwrap();
+
+    let request = server.recv().unwrap();
+    let request = request.into_websocket(None).err().unwrap();
+    request.respond(tiny_http::Response::empty(400)).unwrap();
+
+    let mut content = String::new();
+    client.read_to_string(&mut content).unwrap();
+    assert!(content.starts_with("HTTP/1.1 400"));
+}
+
+#[test]
+fn websocket_unsupported_version_is_rejected() {
+    let (server, mut client) = support::new_one_server_one_client();
+
+    (write!(
+        client,
+        "GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 8\r\n\r\n"
+    ))
+    .unwrap();
+
+    let request = server.recv().unwrap();
+    assert!(!request.has_supported_websocket_version());
+    let request = request.into_websocket(None).err().unwrap();
+    request
+        .respond(tiny_http::websocket::version_not_supported())
+        .unwrap();
+
+    let head = read_response_head(&mut client);
+    assert!(head.starts_with("HTTP/1.1 426"));
+    assert!(head.contains("Sec-WebSocket-Version: 13"));
+}
+
+#[test]
+fn websocket_unmasked_frame_fails_the_connection() {
+    let (server, mut client) = support::new_one_server_one_client();
+
+    (write!(
+        client,
+        "GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n"
+    ))
+    .unwrap();
+
+    let server_thread = thread::spawn(move || {
+        let request = server.recv().unwrap();
+        let (mut receiver, _sender) = request.into_websocket(None).unwrap();
+        assert!(receiver.recv().is_err());
+    });
+
+    let head = read_response_head(&mut client);
+    assert!(head.starts_with("HTTP/1.1 101"));
+
+    client.write_all(&[0x81, 0x02, b'h', b'i']).unwrap();
+    let (opcode, payload) = read_frame(&mut client);
+    assert_eq!(opcode, 0x8);
+    assert_eq!(payload[..2], 1002u16.to_be_bytes());
+
+    server_thread.join().unwrap();
+}